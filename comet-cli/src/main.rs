@@ -1,9 +1,15 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
 use clap::{Parser, Subcommand};
 use comet::{install_package, remove_package};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
 
 #[derive(Parser)]
 #[command(author = "afroraydude", version = "1.0.0", about = "The simple package manager", long_about = None)]
 struct Cli {
+    #[arg(long, global = true, env = "COMET_ROOT", help = "Operate on the system installed under this directory")]
+    root: Option<String>,
+
     #[command(subcommand)]
     command: Commands
 }
@@ -17,6 +23,8 @@ enum Commands {
         local: bool,
         #[arg(short, long)]
         force: bool,
+        #[arg(long, help = "Don't install recommended packages")]
+        no_recommends: bool,
     },
 
     #[command(about = "Remove a package")]
@@ -26,6 +34,22 @@ enum Commands {
         force: bool,
     },
 
+    #[command(about = "Remove packages that were installed as dependencies and are no longer needed")]
+    Autoremove {
+    },
+
+    #[command(about = "Mark packages as explicitly installed or installed as a dependency")]
+    Mark {
+        #[arg(help = "explicit or dependency")]
+        reason: comet::package::InstallReason,
+        package: Vec<String>,
+    },
+
+    #[command(about = "Show the details of an available package")]
+    Info {
+        package: String
+    },
+
     #[command(about = "Update a package")]
     Update {
         package: String
@@ -50,6 +74,18 @@ enum Commands {
     #[command(about = "Update all packages")]
     UpdateAll {
     },
+
+    #[command(about = "Remove every star from the package cache")]
+    Clean {
+    },
+
+    #[command(about = "Remove stars from the package cache that no galaxy offers anymore")]
+    Autoclean {
+    },
+
+    #[command(about = "Show how much space the package cache takes up")]
+    CacheSize {
+    },
 }
 
 fn main() {
@@ -80,21 +116,36 @@ fn main() {
 
     let cli = Cli::parse();
 
+    // everything after this point works on the install root, if one was given
+    if let Some(root) = cli.root.clone() {
+        comet::set_install_root(root);
+    }
+
+    show_download_progress();
+
+    comet::set_warning_callback(Some(Box::new(|message| eprintln!("Warning: {}", message))));
+
     // setup only sets up everything IF it hasn't been setup already
     comet::setup().unwrap();
 
     // if not sudo or admin, exit
-    if !comet::check_permissions() {
+    if !comet::check_permissions().unwrap_or(false) {
         println!("You do not have the proper permissions to use the package manager. Are you root?");
         std::process::exit(1);
     }
 
     match cli.command {
-        Commands::Install { package, local, force } => {
+        Commands::Install { package, local, force, no_recommends } => {
             for p in package.clone() {
-                install_package(p, local, force).unwrap_or_else(|err| {
+                let installed = install_package(p, local, force, !no_recommends).unwrap_or_else(|err| {
                     panic!("Error while installing packages: {}", err);
                 });
+
+                print_downloads(&installed.downloads);
+
+                for (suggested, by) in installed.suggestions {
+                    println!("{} suggests {}", by, suggested);
+                }
             }
         },
         Commands::Remove { package, force } => {
@@ -104,23 +155,73 @@ fn main() {
                 });
             }
         },
+        Commands::Autoremove {} => {
+            let removed = comet::autoremove_packages().unwrap_or_else(|err| {
+                panic!("Error while removing packages: {}", err);
+            });
+
+            if removed.is_empty() {
+                println!("Nothing to remove");
+            }
+
+            for package in removed {
+                println!("Removed {}", package);
+            }
+        },
+        Commands::Mark { reason, package } => {
+            for p in package {
+                comet::mark_package(p.clone(), reason).unwrap_or_else(|err| {
+                    panic!("Error while marking packages: {}", err);
+                });
+
+                println!("Marked {} as {}", p, reason);
+            }
+        },
+        Commands::Info { package } => {
+            match comet::get_package_details(package.clone()).expect("Failed to read package details") {
+                Some(details) => println!("{}", details),
+                None => {
+                    println!("{} is not available, try update-cache", package);
+                    std::process::exit(1);
+                }
+            }
+        },
         Commands::Update { package } => {
             println!("Updating package");
-            comet::update_package(package).expect("Failed to update package");
+            let downloads = comet::update_package(package).expect("Failed to update package");
+            print_downloads(&downloads);
         },
         Commands::Init {} => {
             println!("Initializing system");
-            comet::setup();
+            comet::setup().expect("Failed to initialize system");
             println!("Done!")
         },
         Commands::UpdateCache {} => {
             println!("Updating cache");
-            comet::update_cache_file().expect("Failed to update cache");
+            let update = comet::update_cache_file().expect("Failed to update cache");
+
+            for (galaxy, mirror) in &update.updated {
+                println!("Updated {} from {}", galaxy, mirror);
+            }
+
+            for (galaxy, mirror) in &update.unchanged {
+                println!("{} is unchanged ({})", galaxy, mirror);
+            }
+
+            for (galaxy, err) in &update.failed {
+                println!("Failed to update {}: {}", galaxy, err);
+            }
+
+            if !update.failed.is_empty() {
+                println!("Kept the previously cached packages of the galaxies that failed");
+                std::process::exit(1);
+            }
+
             println!("Done!")
         },
         Commands::List {} => {
             println!("Listing installed packages");
-            let packages = comet::list_packages();
+            let packages = comet::list_packages().expect("Failed to list packages");
 
             for (name, version) in packages {
                 println!("{}: {}", name, version);
@@ -130,7 +231,7 @@ fn main() {
         },
         Commands::ListAvailable {} => {
             println!("Listing available packages");
-            let packages = comet::list_available_packages();
+            let packages = comet::list_available_packages().expect("Failed to list packages");
 
             for (name, version) in packages {
                 println!("{}: {}", name, version);
@@ -138,8 +239,61 @@ fn main() {
         },
         Commands::UpdateAll {} => {
             println!("Updating all packages");
-            comet::update_all_packages();
+            let downloads = comet::update_all_packages().expect("Failed to update packages");
+            print_downloads(&downloads);
             println!("Done!")
         },
+        Commands::Clean {} => {
+            let removed = comet::clean_package_cache().expect("Failed to clean package cache");
+            println!("Removed {} stars, freeing {}", removed.stars, HumanBytes(removed.bytes));
+        },
+        Commands::Autoclean {} => {
+            let removed = comet::autoclean_package_cache().expect("Failed to clean package cache");
+            println!("Removed {} stars, freeing {}", removed.stars, HumanBytes(removed.bytes));
+
+            let usage = comet::package_cache_usage().expect("Failed to read package cache");
+            println!("{} stars left in the package cache, taking up {}", usage.stars, HumanBytes(usage.bytes));
+        },
+        Commands::CacheSize {} => {
+            let usage = comet::package_cache_usage().expect("Failed to read package cache");
+            println!("{} stars in the package cache, taking up {}", usage.stars, HumanBytes(usage.bytes));
+        },
     }
 }
+
+/// Prints where every fetched star came from
+fn print_downloads(downloads: &[(String, String)]) {
+    for (package, mirror) in downloads {
+        println!("Fetched {} from {}", package, mirror);
+    }
+}
+
+/// Draws a progress bar for every star comet downloads
+fn show_download_progress() {
+    let bars = MultiProgress::new();
+    let active: Mutex<HashMap<String, ProgressBar>> = Mutex::new(HashMap::new());
+
+    comet::set_progress_callback(Some(Box::new(move |package, done, total| {
+        let mut active = active.lock().unwrap_or_else(|err| err.into_inner());
+
+        let bar = active.entry(package.to_string()).or_insert_with(|| {
+            let bar = bars.add(ProgressBar::no_length());
+            bar.set_style(ProgressStyle::with_template("{msg:20} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}")
+                .unwrap()
+                .progress_chars("=> "));
+            bar.set_message(package.to_string());
+            bar
+        });
+
+        if let Some(total) = total {
+            bar.set_length(total);
+        }
+
+        bar.set_position(done);
+
+        if total == Some(done) {
+            bar.finish();
+            active.remove(package);
+        }
+    })));
+}
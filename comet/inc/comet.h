@@ -6,24 +6,70 @@
 #include <stdint.h>
 #include <stdlib.h>
 
-/**
- * BEGIN C COMPATIBLE FUNCTIONS *
- */
+bool autoclean_cache(void);
+
+bool autoremove(void);
 
 bool check_perms(void);
 
+bool clean_cache(void);
+
+/**
+ * # Safety
+ * `package` must be null or point to a nul-terminated string
+ */
 bool install(const char *package, bool local, bool force);
 
 const char *list(void);
 
 const char *list_available(void);
 
+/**
+ * # Safety
+ * `package` must be null or point to a nul-terminated string
+ */
+bool mark(const char *package, bool dependency);
+
+/**
+ * # Safety
+ * `package` must be null or point to a nul-terminated string
+ */
 const char *package_details(const char *package);
 
+/**
+ * # Safety
+ * `package` must be null or point to a nul-terminated string
+ */
 bool remove(const char *package, bool force);
 
+/**
+ * Sets a callback for download progress, see `set_progress_callback`
+ *
+ * The callback gets the name of the package, which is only valid during the call, the bytes
+ * downloaded so far and the size of the star, 0 if it isn't known. Null stops reporting progress.
+ */
+void set_progress(void (*callback)(const char*, uint64_t, uint64_t));
+
+/**
+ * # Safety
+ * `root` must be null or point to a nul-terminated string
+ */
+bool set_root(const char *root);
+
+/**
+ * Sets a callback for warnings, see `set_warning_callback`
+ *
+ * The callback gets the warning, which is only valid during the call. Null stops reporting
+ * warnings.
+ */
+void set_warning(void (*callback)(const char*));
+
 bool setup_comet(void);
 
+/**
+ * # Safety
+ * `package` must be null or point to a nul-terminated string
+ */
 bool update(const char *package);
 
 void update_all(void);
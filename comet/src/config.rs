@@ -1,60 +1,274 @@
-
+use std::fmt;
+use std::path::Path;
 use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::galaxy::Galaxy;
+
+/// What to do when a star can't be verified against its checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumPolicy {
+    /// Refuse stars with a wrong checksum, and remote stars without one
+    #[default]
+    Require,
+    /// Print a warning and install the star anyway
+    Warn,
+    /// Don't check checksums at all
+    Off,
+}
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
-    pub repositories: Vec<String>,
+    /// The galaxies packages are installed from, see `Galaxy`
+    pub repositories: Vec<Galaxy>,
     pub keep_package_files: bool,
     pub storage_dir: String,
     pub tmp_dir: String,
+    /// Where downloaded stars are kept, `<storage_dir>/stars` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_cache_dir: Option<String>,
+    /// How many times a download is retried on the same mirror before moving on to the next
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How many stars are downloaded at the same time
+    #[serde(default = "default_parallel_downloads")]
+    pub parallel_downloads: usize,
+    /// How many seconds to wait for another comet process to release the database lock
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout: u64,
+    /// How strictly stars are checked against their checksums
+    #[serde(default)]
+    pub checksum_policy: ChecksumPolicy,
+    /// How galaxies served over HTTP are reached
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// The directory of `*.pub` keys that galaxy and star signatures are checked against
+    #[serde(default = "default_trusted_keys_dir")]
+    pub trusted_keys_dir: String,
+    /// The install root every path in the config is resolved under, not stored in the file
+    #[serde(skip)]
+    pub root: String,
 }
 
 impl Config {
-    pub fn new(repositories: Vec<String>, keep_package_files: bool, storage_dir: String, tmp_dir: String) -> Config {
+    pub fn new(repositories: Vec<Galaxy>, keep_package_files: bool, storage_dir: String, tmp_dir: String) -> Config {
         Config {
             repositories,
             keep_package_files,
             storage_dir,
             tmp_dir,
+            package_cache_dir: None,
+            retries: default_retries(),
+            parallel_downloads: default_parallel_downloads(),
+            lock_timeout: default_lock_timeout(),
+            checksum_policy: ChecksumPolicy::default(),
+            http: HttpConfig::default(),
+            trusted_keys_dir: default_trusted_keys_dir(),
+            root: String::new(),
         }
     }
 
-    pub fn to_string(&self) -> String {
-        serde_yaml::to_string(&self).unwrap()
-    }
-
-    pub fn from_file() -> Result<Config, String> {
-        let config_location;
+    /// The default config for the current platform
+    pub fn default_for_platform() -> Config {
+        let config;
 
         // if linux
         #[cfg(target_os = "linux")]
         {
-            config_location = "/etc/comet/config.yml".to_string();
+            config = Config::new(vec![Galaxy::new("https://repo.wombatlinux.org".to_string())], false, "/var/lib/comet".to_string(), "/tmp".to_string());
         }
 
         // if windows
         #[cfg(target_os = "windows")]
         {
-            config_location = "C:\\Program Files\\Comet\\config.yml".to_string();
+            config = Config::new(vec![Galaxy::new("https://repo.wombatlinux.org".to_string())], false, "C:\\Program Files\\Comet".to_string(), "C:\\Windows\\Temp".to_string());
         }
 
         // if mac
         #[cfg(target_os = "macos")]
         {
-            config_location = "/Library/Application Support/Comet/config.yml".to_string();
+            config = Config::new(vec![Galaxy::new("https://repo.wombatlinux.org".to_string())], false, "/Library/Application Support/Comet".to_string(), "/tmp".to_string());
+        }
+
+        config
+    }
+
+    /// Reads the config file from the current install root (see `crate::install_root`)
+    pub fn from_file() -> Result<Config> {
+        Config::from_root(crate::install_root())
+    }
+
+    /// Reads the config file from the given install root
+    ///
+    /// # Arguments
+    /// * `root` - The install root, or an empty string for the running system
+    pub fn from_root(root: String) -> Result<Config> {
+        let config_location = config_file(&root);
+
+        let config = std::fs::read_to_string(config_location.clone()).map_err(|err| Error::Config {
+            message: format!("Error while reading config file {}", config_location),
+            source: Some(Box::new(Error::io(config_location.clone(), err))),
+        })?;
+
+        let mut config: Config = serde_yaml::from_str(&config).map_err(|err| Error::Config {
+            message: format!("Error while parsing config file {}", config_location),
+            source: Some(Box::new(Error::parse(config_location.clone(), err))),
+        })?;
+
+        config.root = root;
+
+        Ok(config)
+    }
+
+    /// Resolves a path from the config (or any absolute system path) under the install root
+    ///
+    /// # Arguments
+    /// * `path` - The path as it would be on the running system
+    pub fn resolve(&self, path: &str) -> String {
+        resolve_under(&self.root, path)
+    }
+
+    /// The storage directory, resolved under the install root
+    pub fn storage_path(&self) -> String {
+        self.resolve(&self.storage_dir)
+    }
+
+    /// The temp directory, resolved under the install root
+    pub fn tmp_path(&self) -> String {
+        self.resolve(&self.tmp_dir)
+    }
+
+    /// The package cache directory, resolved under the install root
+    pub fn package_cache_path(&self) -> String {
+        match &self.package_cache_dir {
+            Some(dir) => self.resolve(dir),
+            None => format!("{}/stars", self.storage_path()),
+        }
+    }
+
+    /// The trusted keys directory, resolved under the install root
+    pub fn trusted_keys_path(&self) -> String {
+        self.resolve(&self.trusted_keys_dir)
+    }
+
+    /// The install root as handed to install and remove scripts
+    pub fn root_path(&self) -> String {
+        if self.root.is_empty() {
+            "/".to_string()
+        } else {
+            self.root.clone()
+        }
+    }
+}
+
+/// The settings of the HTTP client every download goes through
+///
+/// Certificate and key paths are resolved under the install root, like every other path in the config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// The proxy for `http://` galaxies, `HTTP_PROXY` is used if neither proxy is set
+    pub http_proxy: Option<String>,
+    /// The proxy for `https://` galaxies, `HTTPS_PROXY` is used if neither proxy is set
+    pub https_proxy: Option<String>,
+    /// Hosts, domains and IP ranges that are reached without the proxy
+    pub no_proxy: Vec<String>,
+    /// PEM files of extra CA certificates to trust, each one may hold several certificates
+    pub ca_certificates: Vec<String>,
+    /// A PEM client certificate for galaxies that require mutual TLS
+    pub client_certificate: Option<String>,
+    /// The PKCS #8 PEM private key for `client_certificate`
+    pub client_key: Option<String>,
+    /// How many seconds to wait for a connection, 0 to wait forever
+    pub connect_timeout: u64,
+    /// How many seconds to wait for a server to send more data, 0 to wait forever
+    pub read_timeout: u64,
+    /// The User-Agent header sent to galaxies
+    pub user_agent: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+            ca_certificates: Vec::new(),
+            client_certificate: None,
+            client_key: None,
+            connect_timeout: 30,
+            read_timeout: 30,
+            user_agent: format!("comet/{}", env!("CARGO_PKG_VERSION")),
         }
+    }
+}
 
-        let config = std::fs::read_to_string(config_location.clone()).unwrap();
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = serde_yaml::to_string(&self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", config)
+    }
+}
+
+/// The download retries for config files written before they existed
+fn default_retries() -> u32 {
+    2
+}
 
-        serde_yaml::from_str(&config).unwrap_or_else(|_err| {
-            crate::setup();
+/// The download parallelism for config files written before it existed
+fn default_parallel_downloads() -> usize {
+    4
+}
 
-            // try again
-            let config = std::fs::read_to_string(config_location.clone()).unwrap();
+/// The lock timeout for config files written before it existed
+fn default_lock_timeout() -> u64 {
+    30
+}
+
+/// The trusted keys directory for config files written before it existed
+fn default_trusted_keys_dir() -> String {
+    Path::new(&config_dir("")).join("trusted-keys").to_string_lossy().to_string()
+}
 
-            serde_yaml::from_str(&config).unwrap_or_else(|err| {
-                return Err(format!("Error while parsing config file: {}", err));
-            })
-        })
+/// Resolves an absolute system path under an install root
+///
+/// An empty root means the running system, so the path is returned unchanged
+pub fn resolve_under(root: &str, path: &str) -> String {
+    if root.is_empty() {
+        return path.to_string();
     }
-}
\ No newline at end of file
+
+    let relative = path.trim_start_matches(['/', '\\']);
+
+    Path::new(root).join(relative).to_string_lossy().to_string()
+}
+
+/// The directory containing the config file, resolved under the install root
+pub fn config_dir(root: &str) -> String {
+    let config_location;
+
+    // if linux
+    #[cfg(target_os = "linux")]
+    {
+        config_location = "/etc/comet";
+    }
+
+    // if windows
+    #[cfg(target_os = "windows")]
+    {
+        config_location = "C:\\Program Files\\Comet";
+    }
+
+    // if mac
+    #[cfg(target_os = "macos")]
+    {
+        config_location = "/Library/Application Support/Comet";
+    }
+
+    resolve_under(root, config_location)
+}
+
+/// The config file, resolved under the install root
+pub fn config_file(root: &str) -> String {
+    Path::new(&config_dir(root)).join("config.yml").to_string_lossy().to_string()
+}
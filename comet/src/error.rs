@@ -0,0 +1,147 @@
+use std::fmt;
+
+/// A `Result` using the comet `Error`
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong in libcomet
+///
+/// Variants that wrap another error expose it through `std::error::Error::source`, so callers can
+/// walk the whole chain.
+#[derive(Debug)]
+pub enum Error {
+    /// The config file is missing, unreadable or invalid
+    Config {
+        message: String,
+        source: Option<Box<Error>>,
+    },
+    /// A filesystem operation failed
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    /// A YAML file, version string or other input could not be parsed
+    Parse {
+        context: String,
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    /// A galaxy could not be reached or answered with an error
+    Network {
+        url: String,
+        source: Option<reqwest::Error>,
+    },
+    /// A downloaded or local file doesn't match the checksum it is supposed to have
+    Integrity {
+        package: String,
+        message: String,
+    },
+    /// A signature on a galaxy's repo.yml or a star is missing, untrusted or doesn't match
+    Signature {
+        subject: String,
+        message: String,
+    },
+    /// A package can't be found in the installed database, the cache or on disk
+    PackageNotFound(String),
+    /// The package is already installed
+    AlreadyInstalled(String),
+    /// The package is already at the newest available version
+    UpToDate(String),
+    /// A dependency is missing, can't be installed or blocks the operation
+    Dependency {
+        package: String,
+        message: String,
+    },
+    /// An install or remove script failed to run or exited unsuccessfully
+    Script {
+        script: String,
+        message: String,
+        source: Option<std::io::Error>,
+    },
+    /// An argument handed to the library was not usable
+    InvalidArgument(String),
+    /// Every mirror of a galaxy failed to serve a file
+    Unavailable {
+        resource: String,
+        failures: Vec<(String, Error)>,
+    },
+    /// Another process holds the lock on the comet database
+    Locked {
+        path: String,
+        pid: Option<u32>,
+    },
+    /// An operation failed and undoing its changes failed as well, so the system may be left half changed
+    Rollback {
+        source: Box<Error>,
+        failures: Vec<String>,
+    },
+}
+
+impl Error {
+    pub fn config(message: impl Into<String>) -> Error {
+        Error::Config { message: message.into(), source: None }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Error {
+        Error::Io { context: context.into(), source }
+    }
+
+    pub fn parse(context: impl Into<String>, source: impl std::error::Error + Send + Sync + 'static) -> Error {
+        Error::Parse { context: context.into(), source: Some(Box::new(source)) }
+    }
+
+    pub fn network(url: impl Into<String>, source: reqwest::Error) -> Error {
+        Error::Network { url: url.into(), source: Some(source) }
+    }
+
+    pub fn dependency(package: impl Into<String>, message: impl Into<String>) -> Error {
+        Error::Dependency { package: package.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config { message, source: Some(source) } => write!(f, "{}: {}", message, source),
+            Error::Config { message, source: None } => write!(f, "{}", message),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Parse { context, source: Some(source) } => write!(f, "{}: {}", context, source),
+            Error::Parse { context, source: None } => write!(f, "{}", context),
+            Error::Network { url, source: Some(source) } => write!(f, "Error while fetching {}: {}", url, source),
+            Error::Network { url, source: None } => write!(f, "Error while fetching {}", url),
+            Error::Integrity { package, message } => write!(f, "Integrity check failed for {}: {}", package, message),
+            Error::Signature { subject, message } => write!(f, "Signature check failed for {}: {}", subject, message),
+            Error::PackageNotFound(package) => write!(f, "Package {} not found", package),
+            Error::AlreadyInstalled(package) => write!(f, "Package {} is already installed. Use --force to reinstall", package),
+            Error::UpToDate(package) => write!(f, "Package {} is already up to date", package),
+            Error::Dependency { package, message } => write!(f, "Dependency error for {}: {}", package, message),
+            Error::Script { script, message, source: Some(source) } => write!(f, "Script {} {}: {}", script, message, source),
+            Error::Script { script, message, source: None } => write!(f, "Script {} {}", script, message),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::Unavailable { resource, failures } => {
+                write!(f, "Could not fetch {} from any mirror", resource)?;
+
+                for (mirror, err) in failures {
+                    write!(f, "\n  {}: {}", mirror, err)?;
+                }
+
+                Ok(())
+            }
+            Error::Locked { path, pid: Some(pid) } => write!(f, "Comet database is locked by process {} ({})", pid, path),
+            Error::Locked { path, pid: None } => write!(f, "Comet database is locked by another process ({})", path),
+            Error::Rollback { source, failures } => write!(f, "{} (rolling back also failed: {})", source, failures.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config { source: Some(source), .. } => Some(source.as_ref()),
+            Error::Io { source, .. } => Some(source),
+            Error::Parse { source: Some(source), .. } => Some(source.as_ref()),
+            Error::Network { source: Some(source), .. } => Some(source),
+            Error::Script { source: Some(source), .. } => Some(source),
+            Error::Rollback { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::error::{Error, Result};
+use crate::package::Package;
+use crate::repo::Repository;
+use crate::signature::TrustedKeys;
+
+/// A galaxy entry in the config
+///
+/// In the config file a galaxy can be given as just its URL, or as a map with the fields below.
+/// When several galaxies have the same package, the one with the highest priority wins, even if
+/// another galaxy has a newer version. Between galaxies with the same priority the newest version
+/// wins, and between equal versions the galaxy listed first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "GalaxyEntry")]
+pub struct Galaxy {
+    /// A name for the galaxy, the URL if none is given
+    pub name: String,
+    pub url: String,
+    /// Other URLs serving the same galaxy, tried in order when `url` fails
+    pub mirrors: Vec<String>,
+    /// Higher priorities win over lower ones, the default is 0
+    pub priority: i32,
+    /// A disabled galaxy is neither fetched nor used
+    pub enabled: bool,
+    /// If not empty, only these packages are taken from the galaxy
+    pub include: Vec<String>,
+    /// These packages are never taken from the galaxy
+    pub exclude: Vec<String>,
+}
+
+/// How a galaxy can be written in the config file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GalaxyEntry {
+    Url(String),
+    Full {
+        name: Option<String>,
+        url: String,
+        #[serde(default)]
+        mirrors: Vec<String>,
+        #[serde(default)]
+        priority: i32,
+        #[serde(default = "enabled_by_default")]
+        enabled: bool,
+        #[serde(default)]
+        include: Vec<String>,
+        #[serde(default)]
+        exclude: Vec<String>,
+    },
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl From<GalaxyEntry> for Galaxy {
+    fn from(entry: GalaxyEntry) -> Galaxy {
+        match entry {
+            GalaxyEntry::Url(url) => Galaxy::new(url),
+            GalaxyEntry::Full { name, url, mirrors, priority, enabled, include, exclude } => Galaxy {
+                name: name.unwrap_or_else(|| url.clone()),
+                url,
+                mirrors,
+                priority,
+                enabled,
+                include,
+                exclude,
+            },
+        }
+    }
+}
+
+impl Galaxy {
+    /// An enabled galaxy with the default priority that every package can come from
+    pub fn new(url: String) -> Galaxy {
+        Galaxy {
+            name: url.clone(),
+            url,
+            mirrors: Vec::new(),
+            priority: 0,
+            enabled: true,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    /// The URL of the galaxy followed by its mirrors, in the order they are tried
+    pub fn urls(&self) -> Vec<String> {
+        std::iter::once(self.url.clone()).chain(self.mirrors.iter().cloned()).collect()
+    }
+
+    /// Whether or not a package may be taken from this galaxy
+    ///
+    /// Package lists can use `*` as a wildcard, so `lib*` matches every package starting with "lib"
+    pub fn allows(&self, package: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        if !self.include.is_empty() && !self.include.iter().any(|pattern| matches_pattern(pattern, package)) {
+            return false;
+        }
+
+        !self.exclude.iter().any(|pattern| matches_pattern(pattern, package))
+    }
+}
+
+/// The last repo.yml fetched from a galaxy
+///
+/// It is kept in the storage directory, so the next cache update can ask the server whether it
+/// changed instead of downloading it again, and so its packages are still known if the galaxy
+/// can't be reached.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GalaxyIndex {
+    /// The mirror it was fetched from, which the ETag and Last-Modified values belong to
+    pub mirror: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// The SHA-256 of the repo.yml, to tell whether a new download actually changed anything
+    pub checksum: String,
+    /// The fingerprint of the keys the signature was checked against (see `TrustedKeys::fingerprint`)
+    #[serde(default)]
+    pub keys: String,
+    pub repository: Repository,
+}
+
+impl GalaxyIndex {
+    /// Downloads a galaxy's repo.yml, checking its signature against the trusted keys
+    ///
+    /// If an earlier index from the same mirror is given, and it was checked against the same keys,
+    /// the server is asked whether repo.yml changed since, and it is only downloaded if it did. The signature is read from
+    /// `repo.yml.sig` next to it. If no keys are trusted, nothing is checked; otherwise a missing
+    /// or bad signature is an error.
+    ///
+    /// # Arguments
+    /// * `mirror` - The URL of the galaxy, or a `file://` URL or absolute path for one on disk
+    /// * `keys` - The keys the galaxy must be signed with
+    /// * `previous` - The index fetched last time, if any
+    ///
+    /// # Returns
+    /// * `Result<Option<GalaxyIndex>>` - The new index, or `None` if the server says it hasn't changed
+    pub fn fetch(mirror: &str, keys: &TrustedKeys, previous: Option<&GalaxyIndex>) -> Result<Option<GalaxyIndex>> {
+        let repo_file = crate::transport::join(mirror, "repo.yml");
+
+        // validators only mean something to the server that handed them out, and "not modified"
+        // only means the old index can be used if its signature was checked against these keys
+        let fingerprint = keys.fingerprint();
+        let previous = previous.filter(|previous| previous.mirror == mirror && previous.keys == fingerprint);
+
+        let fetched = crate::transport::fetch_if_changed(
+            &repo_file,
+            previous.and_then(|previous| previous.etag.as_deref()),
+            previous.and_then(|previous| previous.last_modified.as_deref()),
+        )?;
+
+        let Some(fetched) = fetched else {
+            return Ok(None);
+        };
+
+        if !keys.is_empty() {
+            let signature = crate::transport::fetch(&format!("{}.sig", repo_file)).map_err(|err| Error::Signature {
+                subject: repo_file.clone(),
+                message: format!("the signature could not be downloaded ({})", err),
+            })?;
+            keys.verify(&repo_file, fetched.data.as_slice(), &String::from_utf8_lossy(&signature))?;
+        }
+
+        let repository = serde_yaml::from_slice(&fetched.data)
+            .map_err(|err| Error::parse(format!("Error while parsing {}", repo_file), err))?;
+
+        Ok(Some(GalaxyIndex {
+            mirror: mirror.to_string(),
+            etag: fetched.etag,
+            last_modified: fetched.last_modified,
+            checksum: format!("{:x}", Sha256::digest(&fetched.data)),
+            keys: fingerprint,
+            repository,
+        }))
+    }
+
+    /// Where the index of a galaxy is kept, named after a hash of its URL
+    ///
+    /// # Arguments
+    /// * `storage_path` - The storage directory
+    /// * `galaxy` - The galaxy
+    pub fn path(storage_path: &str, galaxy: &Galaxy) -> String {
+        let hash = Sha256::digest(galaxy.url.as_bytes());
+        let name: String = hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        format!("{}/galaxies/{}.yml", storage_path, name)
+    }
+
+    /// Reads a stored index, a missing or unreadable one is treated as not there
+    pub fn from_file(path: &str) -> Option<GalaxyIndex> {
+        let index = std::fs::read_to_string(path).ok()?;
+
+        serde_yaml::from_str(&index).ok()
+    }
+
+    /// Writes the index atomically, creating the directory if needed
+    pub fn to_file(&self, path: &str) -> Result<()> {
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|err| Error::io(format!("Error while creating {}", dir.display()), err))?;
+        }
+
+        let index = serde_yaml::to_string(self).map_err(|err| Error::parse(format!("Error while serializing {}", path), err))?;
+        let tmp_path = format!("{}.tmp", path);
+
+        std::fs::write(&tmp_path, index).map_err(|err| Error::io(format!("Error while writing {}", tmp_path), err))?;
+        std::fs::rename(&tmp_path, path).map_err(|err| Error::io(format!("Error while replacing {}", path), err))
+    }
+}
+
+/// Finds the galaxy a cached package came from
+///
+/// # Arguments
+/// * `galaxies` - The configured galaxies
+/// * `package` - The cached package
+pub fn find<'a>(galaxies: &'a [Galaxy], package: &Package) -> Option<&'a Galaxy> {
+    let url = package.galaxy.as_deref()?;
+
+    galaxies.iter().find(|galaxy| galaxy.url == url)
+}
+
+/// Whether a package from a galaxy should replace the one already in the cache
+///
+/// # Arguments
+/// * `galaxies` - The configured galaxies, in the order they are listed
+/// * `existing` - The package already in the cache
+/// * `candidate` - The package that might replace it
+pub fn prefer(galaxies: &[Galaxy], existing: &Package, candidate: &Package) -> bool {
+    let priority = |package: &Package| find(galaxies, package).map(|galaxy| galaxy.priority).unwrap_or(i32::MIN);
+
+    match priority(candidate).cmp(&priority(existing)) {
+        std::cmp::Ordering::Greater => true,
+        std::cmp::Ordering::Less => false,
+        // same priority, so the newer version wins and a tie keeps the galaxy listed first
+        std::cmp::Ordering::Equal => existing.partial_cmp(candidate) == Some(std::cmp::Ordering::Less),
+    }
+}
+
+/// Drops cached packages that the galaxy config no longer allows
+///
+/// Packages from disabled or removed galaxies, or that a galaxy's include and exclude lists reject,
+/// are taken out. Packages without a galaxy, like local stars, are kept.
+///
+/// # Arguments
+/// * `galaxies` - The configured galaxies
+/// * `available` - The cached packages
+pub fn filter(galaxies: &[Galaxy], available: &mut Repository) {
+    available.packages.retain(|name, package| {
+        if package.galaxy.is_none() {
+            return true;
+        }
+
+        match find(galaxies, package) {
+            Some(galaxy) => galaxy.allows(name),
+            None => false,
+        }
+    });
+}
+
+/// Matches a package name against a pattern where `*` stands for any number of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+
+            // try every possible length for the part the "*" covers
+            (0..=name.len())
+                .filter(|&index| name.is_char_boundary(index))
+                .any(|index| matches_pattern(rest, &name[index..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::{header, response, serve};
+
+    /// A galaxy whose repo.yml has the ETag "v1", and that says so when asked with it
+    fn galaxy() -> (String, crate::transport::tests::Requests) {
+        serve(|head| match header(head, "If-None-Match") {
+            Some("\"v1\"") => response("304 Not Modified", &[], b""),
+            _ => response("200 OK", &[("ETag", "\"v1\"")], b"packages: {}\n"),
+        })
+    }
+
+    fn trusted_keys() -> TrustedKeys {
+        let (_, public) = crate::signature::generate_key().unwrap();
+        let mut keys = TrustedKeys::new();
+        keys.add(crate::signature::public_key_from_string(&public, "test.pub").unwrap());
+        keys
+    }
+
+    #[test]
+    fn unchanged_index_is_not_downloaded_again() {
+        let (url, requests) = galaxy();
+        let keys = TrustedKeys::new();
+
+        let index = GalaxyIndex::fetch(&url, &keys, None).unwrap().unwrap();
+        assert_eq!(index.etag.as_deref(), Some("\"v1\""));
+
+        assert!(GalaxyIndex::fetch(&url, &keys, Some(&index)).unwrap().is_none());
+        assert_eq!(header(&requests.lock().unwrap()[1], "If-None-Match"), Some("\"v1\""));
+    }
+
+    #[test]
+    fn index_checked_against_other_keys_is_downloaded_again() {
+        let (url, requests) = galaxy();
+
+        let index = GalaxyIndex::fetch(&url, &TrustedKeys::new(), None).unwrap().unwrap();
+
+        // now that a key is trusted the old index has to be checked, which needs its signature
+        let keys = trusted_keys();
+        assert!(matches!(GalaxyIndex::fetch(&url, &keys, Some(&index)), Err(Error::Signature { .. })));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(header(&requests[1], "If-None-Match"), None);
+    }
+
+    #[test]
+    fn fingerprint_follows_the_trusted_keys() {
+        let keys = trusted_keys();
+
+        assert_ne!(keys.fingerprint(), TrustedKeys::new().fingerprint());
+        assert_ne!(keys.fingerprint(), trusted_keys().fingerprint());
+        assert_eq!(TrustedKeys::new().fingerprint(), TrustedKeys::default().fingerprint());
+    }
+}
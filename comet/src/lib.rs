@@ -1,164 +1,550 @@
-use std::collections::HashMap;
-use std::env::set_current_dir;
+use std::collections::{HashMap, HashSet};
 use std::ffi::c_char;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use tar::Archive;
-use crate::config::Config;
-use crate::package::{Package, SemVer};
-use crate::repo::Repository;
+use crate::config::{ChecksumPolicy, Config};
+use crate::galaxy::GalaxyIndex;
+use crate::lock::Lock;
+use crate::manifest::{Manifest, Ownership};
+use crate::package::{InstallReason, Package};
+use crate::package_cache::CacheUsage;
+use crate::repo::{CacheUpdate, Repository};
+use crate::resolver::{Plan, Request};
+use crate::signature::TrustedKeys;
+use crate::transaction::Transaction;
 use sha2::{Sha256, Digest};
 
+pub mod error;
 pub mod package;
 pub mod repo;
+pub mod manifest;
+pub mod resolver;
+pub mod galaxy;
+pub mod transaction;
+pub mod star;
+pub mod signature;
+pub mod package_cache;
 mod config;
+mod lock;
+mod transport;
+
+pub use crate::error::{Error, Result};
+
+/// The install root every comet path is resolved under, empty for the running system
+static INSTALL_ROOT: RwLock<String> = RwLock::new(String::new());
+
+/// Called with the name of a package, the bytes downloaded so far and the size of its star, if known
+pub type ProgressCallback = Box<dyn Fn(&str, u64, Option<u64>) + Send + Sync>;
+
+/// The callback set by `set_progress_callback`
+static PROGRESS_CALLBACK: RwLock<Option<ProgressCallback>> = RwLock::new(None);
+
+/// Called with a problem that comet carries on despite, like a star failing its integrity check
+/// under the `warn` checksum policy
+pub type WarningCallback = Box<dyn Fn(&str) + Send + Sync>;
+
+/// The callback set by `set_warning_callback`
+static WARNING_CALLBACK: RwLock<Option<WarningCallback>> = RwLock::new(None);
+
+/// The outcome of installing a package
+#[derive(Debug, Default)]
+pub struct InstallResult {
+    /// The stars that were fetched, with the mirror each one came from, or the package cache
+    /// directory if it was already there
+    pub downloads: Vec<(String, String)>,
+    /// The packages that were suggested but aren't installed, with the package suggesting each one
+    pub suggestions: Vec<(String, String)>,
+}
 
 /// Installs a package from a repository or a local file
 ///
-/// Where applicable, also installs dependencies
+/// Where applicable, also installs dependencies. The whole set of packages is resolved up front
+/// (see `resolver::resolve`), so nothing is touched if the dependencies can't be satisfied.
+/// Recommended packages are installed too where possible, suggested ones are only returned.
 ///
 /// # Arguments
 /// * `package` - The name of the package to install
 /// * `local` - Whether or not to install a local package
 /// * `force` - Whether or not to force install a package
-pub fn install_package(package: String, local: bool, force: bool) -> Result<(), String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(format!("Error while reading config file: {}", err));
-        }
+/// * `recommends` - Whether or not to install recommended packages
+///
+/// # Returns
+/// * `Result<InstallResult>` - Where the stars came from, and what was suggested
+pub fn install_package(package: String, local: bool, force: bool, recommends: bool) -> Result<InstallResult> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
+
+    // a local star is copied to the temp directory, the package file would be the "package" argument
+    let staged = match local {
+        true => Some(stage_local_star(&config, &package)?),
+        false => None,
     };
 
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let installed = Repository::from_file(repo_file);
+    let result = install_staged(&config, package, staged.as_ref().map(|(file_path, _)| file_path.as_str()), force, recommends);
 
-    // if the package is already installed, then exit if force is false
-    if installed.get_package(package.clone()).is_some() && !force {
-        return Err(format!("Package {} is already installed. Use --force to reinstall", package));
+    // a copy left behind by a failed install would be mistaken for the star next time
+    if let (Err(_), Some((file_path, true))) = (&result, &staged) {
+        let _ = std::fs::remove_file(file_path);
     }
 
-    let file_path;
-    let package_name;
+    result
+}
 
-    if !local {
-        package_name = package.clone();
+/// Resolves and installs a package for `install_package`, once a local star has been staged
+///
+/// # Arguments
+/// * `config` - The config to install under
+/// * `package` - The name of the package, or the path of the local star
+/// * `staged` - The copy of the local star in the temp directory, if installing a local package
+/// * `force` - Whether or not to force install a package
+/// * `recommends` - Whether or not to install recommended packages
+fn install_staged(config: &Config, package: String, staged: Option<&str>, force: bool, recommends: bool) -> Result<InstallResult> {
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let installed = Repository::from_file(repo_file)?;
+
+    let mut available = load_available(config)?;
+
+    let mut local_star = None;
+    let name;
+
+    if let Some(file_path) = staged {
+        // a local star doesn't need a signature, but one that comes with it has to be valid
+        let signature_file = format!("{}.sig", package);
+        let keys = TrustedKeys::from_dir(&config.trusted_keys_path())?;
 
-        if download_package(package.clone()).is_err() {
-            return Err(format!("Package {} not found", package));
+        if Path::new(&signature_file).exists() && !keys.is_empty() {
+            let signature = std::fs::read_to_string(&signature_file)
+                .map_err(|err| Error::io(format!("Error while reading {}", signature_file), err))?;
+
+            verify_star(&keys, file_path, &signature)?;
         }
 
-        file_path = format!("{}/{}.star", config.tmp_dir, package);
+        let info = read_star_info(file_path)?;
+
+        // check the star against a "<star>.sha256" next to it, or what the galaxy says this version should be
+        let expected = match read_sidecar_checksum(&package)? {
+            Some(checksum) => Some(checksum),
+            None => available.get_package(info.name.clone())
+                .filter(|cached| *cached == &info)
+                .and_then(|cached| cached.checksum.clone()),
+        };
+
+        let digest = hash_file(file_path)?;
+        verify_checksum(config, &info.name, &digest, expected, false)?;
+
+        // the local star is resolved like any other available package
+        name = info.name.clone();
+        available.add_package(info);
+        local_star = Some((name.clone(), file_path.to_string(), digest));
     } else {
-        // if package isn't in the temp directory, then copy it to the temp directory
-        // the package file would be the "package" argument
-        let package_path = Path::new(&package);
+        name = package.clone();
+    }
+
+    // if the package is already installed, then exit if force is false
+    if installed.get_package(name.clone()).is_some() && !force {
+        return Err(Error::AlreadyInstalled(name));
+    }
+
+    let mut request = Request::new(name.clone());
+    request.from_available = force || staged.is_some();
+    request.recommends = recommends;
+
+    let plan = resolver::resolve(&installed, &available, &[request])?;
 
-        if !package_path.exists() {
-            return Err(format!("Package {} not found", package));
+    let downloads = install_plan(config, &plan, local_star, &[name])?;
+
+    Ok(InstallResult {
+        downloads,
+        suggestions: plan.suggestions(&installed),
+    })
+}
+
+/// Installs every step of a plan as a single transaction
+///
+/// Every star is downloaded and extracted before anything is installed. If any step fails, every
+/// change made so far is undone and the installed database is left untouched.
+///
+/// # Arguments
+/// * `config` - The config to install under
+/// * `plan` - The plan to install
+/// * `local_star` - The name, star file and checksum of a local package in the plan, which isn't downloaded
+/// * `explicit` - The packages that were asked for, see `install_reason`
+///
+/// # Returns
+/// * `Result<Vec<(String, String)>>` - The stars that were fetched, with the mirror each one came from
+fn install_plan(config: &Config, plan: &Plan, local_star: Option<(String, String, String)>, explicit: &[String]) -> Result<Vec<(String, String)>> {
+    let mut transaction = Transaction::begin(config)?;
+
+    let mut downloads = Vec::new();
+
+    let star_files = match apply_plan(config, plan, local_star, explicit, &mut downloads, &mut transaction) {
+        Ok(star_files) => star_files,
+        Err(err) => return transaction.rollback(err).map(|_| Vec::new()),
+    };
+
+    transaction.commit()?;
+
+    // if keep_package_files is false, remove the stars in the temp directory; the package cache is
+    // only ever emptied by `clean_package_cache` and `autoclean_package_cache`
+    if !config.keep_package_files {
+        let cache_dir = config.package_cache_path();
+
+        for file_path in star_files.iter().filter(|file_path| Path::new(file_path).parent() != Some(Path::new(&cache_dir))) {
+            std::fs::remove_file(file_path)
+                .map_err(|err| Error::io(format!("Error while removing {}", file_path), err))?;
         }
+    }
+
+    Ok(downloads)
+}
+
+/// Stages and installs every step of a plan inside a transaction
+///
+/// Every star that is fetched is added to `downloads`, with the mirror it came from.
+///
+/// # Returns
+/// * `Result<Vec<String>>` - The star files that were installed
+fn apply_plan(config: &Config, plan: &Plan, local_star: Option<(String, String, String)>, explicit: &[String], downloads: &mut Vec<(String, String)>, transaction: &mut Transaction) -> Result<Vec<String>> {
+    let mut star_files = Vec::new();
+    let mut staged = Vec::new();
+
+    let remote: Vec<String> = plan.steps.iter()
+        .map(|step| step.package.name.clone())
+        .filter(|name| local_star.as_ref().map(|(local, _, _)| local != name).unwrap_or(true))
+        .collect();
+
+    let mut fetched = fetch_stars(config, &remote)?;
+
+    // fetch and extract everything first, so a bad download doesn't leave a half installed plan
+    for step in &plan.steps {
+        let (file_path, digest) = match &local_star {
+            Some((name, file_path, digest)) if name == &step.package.name => (file_path.clone(), digest.clone()),
+            _ => {
+                let (file_path, digest, mirror) = fetched.remove(&step.package.name)
+                    .ok_or_else(|| Error::PackageNotFound(step.package.name.clone()))?;
+
+                downloads.push((step.package.name.clone(), mirror));
+                (file_path, digest)
+            }
+        };
 
-        let file_name = package_path.file_name().unwrap().to_str().unwrap();
-        file_path = format!("{}/{}", config.tmp_dir, file_name);
+        let package_dir = format!("{}/{}", transaction.staging_dir(), step.package.name);
+        let ownership = stage_star(&file_path, &package_dir)?;
+        let reason = install_reason(&transaction.installed, plan, explicit, &step.package.name);
+
+        star_files.push(file_path);
+        staged.push((package_dir, ownership, digest, reason));
+    }
 
-        if !Path::new(&file_path).exists() {
-            std::fs::copy(package_path, file_path.clone()).unwrap();
+    // replaced packages go first, so the packages taking over can install the same files
+    for removal in &plan.removals {
+        remove_files(config, &removal.package.name, transaction)?;
+    }
+
+    for (package_dir, ownership, digest, reason) in staged {
+        install_star(config, package_dir, &ownership, digest, reason, transaction)?;
+    }
+
+    Ok(star_files)
+}
+
+/// Why a package in a plan is installed
+///
+/// Packages that were asked for are explicit. Anything else keeps the reason of the package it
+/// upgrades, or is explicit if it replaces an explicit package, and is a dependency otherwise.
+///
+/// # Arguments
+/// * `installed` - The installed packages from before the plan
+/// * `plan` - The plan being installed
+/// * `explicit` - The packages that were asked for
+/// * `package` - The package in the plan
+fn install_reason(installed: &Repository, plan: &Plan, explicit: &[String], package: &str) -> InstallReason {
+    if explicit.iter().any(|name| name == package) {
+        return InstallReason::Explicit;
+    }
+
+    if let Some(current) = installed.get_package(package.to_string()) {
+        return current.reason;
+    }
+
+    let replaces_explicit = plan.removals.iter()
+        .any(|removal| removal.replaced_by == package && removal.package.reason.is_explicit());
+
+    match replaces_explicit {
+        true => InstallReason::Explicit,
+        false => InstallReason::Dependency,
+    }
+}
+
+/// Downloads several stars at once, up to `parallel_downloads` at a time
+///
+/// Each star is verified as soon as it is downloaded (see `fetch_star`). Once a download fails no
+/// new ones are started, and the error of the first failing package in `packages` is returned.
+///
+/// # Arguments
+/// * `config` - The config to download under
+/// * `packages` - The names of the packages to download
+///
+/// # Returns
+/// * `Result<HashMap<String, (String, String, String)>>` - The path, SHA-256 and mirror of every star
+///   (see `fetch_star`), by package name
+fn fetch_stars(config: &Config, packages: &[String]) -> Result<HashMap<String, (String, String, String)>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(packages.iter().map(|_| None).collect::<Vec<_>>());
+
+    let workers = config.parallel_downloads.clamp(1, packages.len().max(1));
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+
+                    let Some(package) = packages.get(index) else {
+                        break;
+                    };
+
+                    let result = fetch_star(config, package.clone());
+
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+
+                    results.lock().unwrap_or_else(|err| err.into_inner())[index] = Some(result);
+                }
+            });
         }
+    });
+
+    let mut stars = HashMap::new();
 
-        // package name is filename without extension
-        package_name = file_name.split(".").collect::<Vec<&str>>()[0].to_string();
+    for (package, result) in packages.iter().zip(results.into_inner().unwrap_or_else(|err| err.into_inner())) {
+        match result {
+            Some(Ok(star)) => {
+                stars.insert(package.clone(), star);
+            }
+            Some(Err(err)) => return Err(err),
+            // never started because an earlier download failed
+            None => {}
+        }
     }
 
-    // lets move to the temp directory
-    if set_current_dir(config.tmp_dir.clone()).is_err() {
-        return Err(format!("Error while moving to temp directory: {}", config.tmp_dir));
+    Ok(stars)
+}
+
+/// Copies a local star into the temp directory, replacing an earlier copy
+///
+/// # Returns
+/// * `Result<(String, bool)>` - The path of the copy, and false if the star already was in the temp directory
+fn stage_local_star(config: &Config, package: &str) -> Result<(String, bool)> {
+    let package_path = Path::new(package);
+
+    if !package_path.exists() {
+        return Err(Error::PackageNotFound(package.to_string()));
     }
 
-    // extract the package
-    let file = match File::open(file_path.clone()) {
-        Ok(file) => file,
-        Err(err) => {
-            return Err(format!("Error while opening package file: {}", err));
+    let file_name = match package_path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().to_string(),
+        None => {
+            return Err(Error::InvalidArgument(format!("{} is not a package file", package)));
         }
     };
+    let file_path = format!("{}/{}", config.tmp_path(), file_name);
+
+    // copying a file onto itself would empty it
+    if let (Ok(from), Ok(to)) = (package_path.canonicalize(), Path::new(&file_path).canonicalize()) {
+        if from == to {
+            return Ok((file_path, false));
+        }
+    }
 
-    let mut archive = Archive::new(file);
+    // always copy, whatever is there from an earlier install may be an older build of the star
+    std::fs::copy(package_path, file_path.clone())
+        .map_err(|err| Error::io(format!("Error while copying {} to {}", package, file_path), err))?;
 
-    // create the package directory
-    let package_dir = format!("{}/{}", config.tmp_dir, package_name);
+    Ok((file_path, true))
+}
+
+/// Reads `info.yml` from a star without extracting anything else
+fn read_star_info(file_path: &str) -> Result<Package> {
+    let mut archive = star::open(file_path)?;
+    let entries = archive.entries()
+        .map_err(|err| Error::io(format!("Error while reading package file {}", file_path), err))?;
+
+    for entry in entries {
+        let mut entry = entry.map_err(|err| Error::io(format!("Error while reading package file {}", file_path), err))?;
+
+        let is_info = match entry.path() {
+            Ok(path) => path.components().filter(|c| !matches!(c, Component::CurDir)).eq([Component::Normal("info.yml".as_ref())]),
+            Err(_) => false,
+        };
 
-    if !Path::new(&package_dir).exists() {
-        std::fs::create_dir(&package_dir).unwrap();
+        if is_info {
+            let mut info = String::new();
+            entry.read_to_string(&mut info)
+                .map_err(|err| Error::io(format!("Error while reading info.yml from {}", file_path), err))?;
+
+            return Package::from_string(info);
+        }
     }
 
-    archive.unpack(&package_dir).unwrap();
+    Err(Error::Parse {
+        context: format!("Package file {} has no info.yml", file_path),
+        source: None,
+    })
+}
+
+/// Extracts a star into a directory
+///
+/// # Arguments
+/// * `file_path` - The star file
+/// * `package_dir` - The directory to extract into
+///
+/// # Returns
+/// * `Result<Ownership>` - The owner of every path under `package/`, keyed by system path
+fn stage_star(file_path: &str, package_dir: &str) -> Result<Ownership> {
+    // stars may be compressed, which star::open works out from the file itself
+    let mut archive = star::open(file_path)?;
+
+    // create the package directory
+    std::fs::create_dir_all(package_dir)
+        .map_err(|err| Error::io(format!("Error while creating {}", package_dir), err))?;
 
-    // move to the package directory
-    set_current_dir(package_dir.clone()).unwrap();
+    // unpack entry by entry so the owners recorded in the star survive an unprivileged extract
+    archive.set_preserve_permissions(true);
 
+    unpack_star(&mut archive, package_dir)
+}
+
+/// Installs a single extracted star, without looking at its dependencies
+///
+/// # Arguments
+/// * `config` - The config to install under
+/// * `package_dir` - The directory the star was extracted to
+/// * `ownership` - The owners recorded in the star
+/// * `digest` - The SHA-256 of the star, recorded in the installed database
+/// * `transaction` - The transaction every change is recorded in
+fn install_star(config: &Config, package_dir: String, ownership: &Ownership, digest: String, reason: InstallReason, transaction: &mut Transaction) -> Result<()> {
     // read the package file
     let package_file = format!("{}/info.yml", package_dir);
 
-    let package = Package::from_file(package_file.clone());
+    let mut package = Package::from_file(package_file.clone())?;
 
-    // check dependencies
-    // dependencies are a hashmap of package name to minimum version
-    for (key, value) in package.dependencies.clone() {
-        if check_dependency(key.clone(), value.clone()).is_err() {
-            return Err(format!("Dependency {} not found", key));
-        }
+    // remember exactly which star was installed, so it can be audited later
+    package.checksum = Some(digest);
+    package.reason = reason;
+
+    // dependencies were taken care of by the plan, so we can just install the package
+
+    let manifests_dir = format!("{}/manifests", config.storage_path());
+    let manifest_file = format!("{}/{}.yml", manifests_dir, package.name);
+
+    // what the installed version put on disk, if this is an upgrade or reinstall
+    let previous = match Path::new(&manifest_file).exists() {
+        true => Some(Manifest::from_file(manifest_file.clone())?),
+        false => None,
+    };
+
+    // copy the payload to the system and record which files the package owns
+    let payload_dir = format!("{}/package", package_dir);
+    let manifest = manifest::deploy(package.name.clone(), Path::new(&payload_dir), &config.root, ownership, previous.as_ref(), transaction)?;
+
+    // paths only the installed version had are removed like they would be by `comet remove`
+    if let Some(previous) = previous {
+        let current: HashSet<&str> = manifest.files.iter().map(|entry| entry.path.as_str()).collect();
+
+        let mut stale = Manifest::new(package.name.clone());
+        stale.files = previous.files.into_iter().filter(|entry| !current.contains(entry.path.as_str())).collect();
+
+        let shared = owned_paths(&manifests_dir, &package.name)?;
+        manifest::remove(&stale, &config.root, &shared, transaction)?;
     }
 
-    // since check dependency installs the package if it can, we can just install the package
-    // if it isn't installed
+    transaction.create_dir(Path::new(&manifests_dir))?;
+    transaction.write_file(Path::new(&manifest_file), manifest.to_string().as_bytes())?;
 
-    // to install, we run the "install" script
+    // then we run the "install" script
 
     // first check if the install script exists
     let install_script = format!("{}/install", package_dir);
+    let uninstall_script = format!("{}/remove", package_dir);
 
     if Path::new(&install_script).exists() {
-        // if it does, run it
-        let command = format!("sh {}", install_script);
-        std::process::Command::new(command).spawn().unwrap();
+        // if it does, run it, and undo it with the remove script if the transaction fails
+        run_script(config, install_script.clone(), package_dir.clone())?;
+        transaction.ran_script(uninstall_script.clone(), package_dir.clone());
     }
 
-    // move the uninstall script to the storage/scripts directory
-    let uninstall_script = format!("{}/remove", package_dir);
+    // move the uninstall scripts to the storage/scripts directory
+    // "remove" runs before the package files are deleted, "post-remove" after, and "install" is
+    // kept to undo them if the removal fails
+    let post_remove_script = format!("{}/post-remove", package_dir);
+    let scripts_dir = format!("{}/scripts", config.storage_path());
 
     // create the scripts directory if it doesn't exist
-    if !Path::new(&format!("{}/scripts", config.storage_dir)).exists() {
-        std::fs::create_dir(format!("{}/scripts", config.storage_dir)).unwrap();
+    transaction.create_dir(Path::new(&scripts_dir))?;
+
+    let scripts = [
+        (uninstall_script, format!("{}/{}", scripts_dir, package.name)),
+        (post_remove_script, format!("{}/{}.post-remove", scripts_dir, package.name)),
+        (install_script, format!("{}/{}.install", scripts_dir, package.name)),
+    ];
+
+    for (script, script_name) in scripts {
+        if Path::new(&script).exists() {
+            transaction.copy_file(Path::new(&script), Path::new(&script_name))?;
+        } else if Path::new(&script_name).exists() {
+            // an older version had this script, but this one doesn't
+            transaction.remove(Path::new(&script_name))?;
+        }
     }
 
-    if Path::new(&uninstall_script).exists() {
-        let script_name = format!("{}/scripts/{}", config.storage_dir, package_name);
-        std::fs::copy(uninstall_script, script_name).unwrap();
-    }
+    // add the package to the repo, which is saved when the transaction is committed
+    transaction.installed.add_package(package);
+
+    Ok(())
+}
 
-    // move back to the temp directory
-    set_current_dir(config.tmp_dir.clone()).unwrap();
+/// Unpacks a star into a directory, collecting the owners of its payload
+///
+/// # Arguments
+/// * `archive` - The star to unpack
+/// * `package_dir` - The directory to unpack into
+///
+/// # Returns
+/// * `Result<Ownership>` - The owner of every path under `package/`, keyed by system path
+fn unpack_star<R: Read>(archive: &mut Archive<R>, package_dir: &str) -> Result<Ownership> {
+    let mut ownership = Ownership::new();
 
-    // remove the package directory
-    std::fs::remove_dir_all(package_dir.clone()).unwrap();
+    let entries = archive.entries()
+        .map_err(|err| Error::io("Error while reading package file", err))?;
 
-    // remove the package file
-    std::fs::remove_file(file_path.clone()).unwrap();
+    for entry in entries {
+        let mut entry = entry.map_err(|err| Error::io("Error while reading package file", err))?;
 
-    // add the package to the repo
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let mut repo = Repository::from_file(repo_file);
+        // payload paths are stored as "package/<path>", which is "/<path>" on the system
+        if let Ok(path) = entry.path() {
+            let mut components = path.components().filter(|c| !matches!(c, Component::CurDir));
 
-    repo.add_package(package);
+            if components.next() == Some(Component::Normal("package".as_ref())) {
+                let system_path: PathBuf = std::iter::once(Component::RootDir).chain(components).collect();
+                let uid = entry.header().uid().unwrap_or(0) as u32;
+                let gid = entry.header().gid().unwrap_or(0) as u32;
 
-    repo.to_file(format!("{}/repo.yml", config.storage_dir));
+                ownership.insert(system_path.to_string_lossy().to_string(), (uid, gid));
+            }
+        }
 
-    // if keep_package_files is false, remove the package file from the temp directory
-    if !config.keep_package_files {
-        std::fs::remove_file(file_path.clone()).unwrap();
+        entry.unpack_in(package_dir)
+            .map_err(|err| Error::io("Error while extracting package file", err))?;
     }
 
-    Ok(())
+    Ok(ownership)
 }
 
 /// Uninstalls a package
@@ -166,529 +552,799 @@ pub fn install_package(package: String, local: bool, force: bool) -> Result<(),
 /// # Arguments
 /// * `package` - The name of the package to uninstall
 /// * `force` - Whether or not to force uninstall a package
-pub fn remove_package(package: String, force: bool) -> Result<(), String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(format!("Error while reading config file: {}", err));
-        }
-    };
+pub fn remove_package(package: String, force: bool) -> Result<()> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
 
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let mut repo = Repository::from_file(repo_file);
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let repo = Repository::from_file(repo_file)?;
 
     let package_file = repo.get_package(package.clone());
 
     if package_file.is_none() {
-        return Err(format!("Package {} not found", package));
+        return Err(Error::PackageNotFound(package));
     }
 
     if repo.is_dependency(package.clone()) && !force {
-        return Err(format!("Package {} is a dependency of another package. Use --force to remove", package));
+        return Err(Error::dependency(package, "it is a dependency of another package. Use --force to remove"));
     }
 
-    // remove the package
+    // remove the package as a single transaction, so a failure puts everything back
+    let mut transaction = Transaction::begin(&config)?;
 
-    // go to the storage/scripts directory
-    set_current_dir(format!("{}/scripts", config.storage_dir)).unwrap();
+    match remove_files(&config, &package, &mut transaction) {
+        Ok(()) => transaction.commit(),
+        Err(err) => transaction.rollback(err),
+    }
+}
 
-    // run the remove script
-    let script_name = format!("{}/scripts/{}", config.storage_dir, package);
+/// Runs a package's remove scripts and deletes its files inside a transaction
+///
+/// Once a remove script has run, the package's install script is journaled to undo it, so a
+/// rollback puts the files back and then runs the install script again. A remove script that fails
+/// isn't undone, and neither are the remove scripts of packages installed before install scripts
+/// were kept.
+///
+/// # Arguments
+/// * `config` - The config to remove under
+/// * `package` - The name of the package to remove
+/// * `transaction` - The transaction every change is recorded in
+fn remove_files(config: &Config, package: &str, transaction: &mut Transaction) -> Result<()> {
+    // run the pre-remove script from the storage/scripts directory
+    let scripts_dir = format!("{}/scripts", config.storage_path());
+    let script_name = format!("{}/{}", scripts_dir, package);
+    let post_script_name = format!("{}/{}.post-remove", scripts_dir, package);
+    let install_script_name = format!("{}/{}.install", scripts_dir, package);
 
     if Path::new(&script_name).exists() {
-        std::process::Command::new(script_name).spawn().unwrap();
+        run_script(config, script_name.clone(), scripts_dir.clone())?;
+        transaction.ran_script(install_script_name.clone(), scripts_dir.clone());
+    }
+
+    // delete everything the package put on disk, keeping paths other packages also own
+    let manifests_dir = format!("{}/manifests", config.storage_path());
+    let manifest_file = format!("{}/{}.yml", manifests_dir, package);
+
+    if Path::new(&manifest_file).exists() {
+        let manifest = Manifest::from_file(manifest_file.clone())?;
+        let shared = owned_paths(&manifests_dir, package)?;
+
+        manifest::remove(&manifest, &config.root, &shared, transaction)?;
     }
 
-    // remove the package from the repo
-    repo.remove_package(package.clone());
+    // then run the post-remove script, which the install script undoes too
+    if Path::new(&post_script_name).exists() {
+        run_script(config, post_script_name.clone(), scripts_dir.clone())?;
+
+        if !Path::new(&script_name).exists() {
+            transaction.ran_script(install_script_name.clone(), scripts_dir.clone());
+        }
+    }
+
+    // remove the package from the repo, which is saved when the transaction is committed
+    transaction.installed.remove_package(package.to_string());
+
+    // the package is gone, so are its scripts and manifest
+    for file in [script_name, post_script_name, install_script_name, manifest_file] {
+        if Path::new(&file).exists() {
+            transaction.remove(Path::new(&file))?;
+        }
+    }
 
     Ok(())
 }
 
-/// Updates a package
+/// Removes the packages that were only installed as dependencies and that nothing needs anymore
 ///
-/// Basically just installs it again but with the --force flag
+/// See `Repository::orphans` for what is still needed. Packages are removed before what they
+/// depend on, so their remove scripts can still use it. Everything is removed in a single
+/// transaction, so a failure puts every package back.
 ///
-/// # Arguments
-/// * `package` - The name of the package to update
-pub fn update_package(package: String) -> Result<(), String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(format!("Error while reading config file: {}", err));
-        }
-    };
+/// # Returns
+/// * `Result<Vec<String>>` - The packages that were removed, in the order they were removed
+pub fn autoremove_packages() -> Result<Vec<String>> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
 
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let repo = Repository::from_file(repo_file);
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let repo = Repository::from_file(repo_file)?;
 
-    let package_file = repo.get_package(package.clone());
+    let orphans = repo.orphans();
 
-    if package_file.is_none() {
-        return Err(format!("Package {} not found. To install, use the install command", package));
+    if orphans.is_empty() {
+        return Ok(orphans);
     }
 
-    // check if an update is available using the cache
-    let cache_file = format!("{}/cache.yml", config.storage_dir);
-    let cache = Repository::from_file(cache_file);
-    let cache_package = cache.get_package(package.clone());
+    let mut transaction = Transaction::begin(&config)?;
 
-    if cache_package.is_none() {
-        return Err(format!("Package {} not found. Update the cache and try again", package));
+    for package in &orphans {
+        if let Err(err) = remove_files(&config, package, &mut transaction) {
+            return transaction.rollback(err).map(|_| Vec::new());
+        }
     }
 
-    let cache_package = cache_package.unwrap();
-    let cache_version = SemVer::from_string(cache_package.version.clone());
-    let installed_package = repo.get_package(package.clone()).unwrap();
+    transaction.commit()?;
 
-    let installed_version = SemVer::from_string(installed_package.version.clone());
+    Ok(orphans)
+}
 
-    if cache_version <= installed_version {
-        return Err(format!("Package {} is already up to date", package));
+/// Changes why an installed package is installed
+///
+/// # Arguments
+/// * `package` - The name of the installed package
+/// * `reason` - The new reason, packages marked as dependencies can be removed by `autoremove_packages`
+pub fn mark_package(package: String, reason: InstallReason) -> Result<()> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
+
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let mut repo = Repository::from_file(repo_file.clone())?;
+
+    match repo.packages.get_mut(&package) {
+        Some(installed) => installed.reason = reason,
+        None => return Err(Error::PackageNotFound(package)),
     }
 
-    // if the package is found, update it by installing it again
-    if install_package(package.clone(), false, true).is_err() {
-        return Err(format!("Error while updating package {}", package));
+    repo.to_file(repo_file)
+}
+
+/// Collects every path owned by installed packages other than the given one
+///
+/// # Arguments
+/// * `manifests_dir` - The directory the package manifests are stored in
+/// * `except` - The package whose manifest is skipped
+fn owned_paths(manifests_dir: &str, except: &str) -> Result<HashSet<String>> {
+    let mut paths = HashSet::new();
+
+    let entries = std::fs::read_dir(manifests_dir)
+        .map_err(|err| Error::io(format!("Error while reading manifests directory {}", manifests_dir), err))?;
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_name().to_string_lossy() == format!("{}.yml", except) {
+            continue;
+        }
+
+        let manifest = Manifest::from_file(entry.path().to_string_lossy().to_string())?;
+
+        for file in manifest.files {
+            paths.insert(file.path);
+        }
     }
 
-    Ok(())
+    Ok(paths)
 }
 
-
-/// Downloads a package from a repository and stores it in the temp directory
+/// Updates a package
+///
+/// Basically just installs it again but with the --force flag. If an available package replaces
+/// it (see `Package::replaces`), that package is installed and this one removed instead.
 ///
 /// # Arguments
-/// * `package` - The name of the package to download
-pub fn download_package(package: String) -> Result<(), String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(format!("Error while reading config file: {}", err));
-        }
+/// * `package` - The name of the package to update
+///
+/// # Returns
+/// * `Result<Vec<(String, String)>>` - The stars that were fetched, with the mirror each one came from
+pub fn update_package(package: String) -> Result<Vec<(String, String)>> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
+
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let repo = Repository::from_file(repo_file)?;
+
+    let installed_package = match repo.get_package(package.clone()) {
+        Some(installed_package) => installed_package,
+        None => return Err(Error::PackageNotFound(package)),
     };
 
-    if !package_is_cached(package.clone()) {
-        return Err(format!("Package {} not found. Update the cache and try again", package));
-    }
+    // check if an update is available using the cache
+    let cache = load_available(&config)?;
 
-    // TODO: Determine if we should just attempt to download the package from the repositories
+    if let Some(replacement) = resolver::replacement(&repo, &cache, installed_package) {
+        let mut request = Request::new(replacement.name.clone());
+        request.from_available = true;
 
-    // package is found, lets download it from an available repository
-    let repository = find_package_file_from_repo(package.clone());
+        let plan = resolver::resolve(&repo, &cache, &[request])?;
 
-    if repository.is_none() {
-        return Err(format!("Package {} not found. Update the cache and try again", package));
+        return install_plan(&config, &plan, None, &[]);
     }
 
-    let repository = repository.unwrap();
-    let url = format!("{}/{}.star", repository, package);
+    let cache_package = match cache.get_package(package.clone()) {
+        Some(cache_package) => cache_package,
+        None => return Err(Error::PackageNotFound(package)),
+    };
 
-    let mut response = reqwest::blocking::get(&url).unwrap();
+    // the epoch and release count too, so a rebuild of the same upstream version is an update
+    let cache_version = cache_package.full_version()?;
+    let installed_version = installed_package.full_version()?;
 
-    let mut file = File::create(format!("{}/{}.star", config.tmp_dir, package)).unwrap();
-    response.copy_to(&mut file).unwrap();
+    if cache_version <= installed_version {
+        return Err(Error::UpToDate(package));
+    }
 
-    // load the cache
-    let cache_file = format!("{}/cache.yml", config.storage_dir);
-    let mut cache = Repository::from_file(cache_file);
+    // if the package is found, update it by installing the new version and anything it needs
+    let mut request = Request::new(package);
+    request.from_available = true;
 
-    // grab the package from the cache
-    let package_file = cache.get_package(package.clone()).unwrap();
+    let plan = resolver::resolve(&repo, &cache, &[request])?;
 
-    // check the checksum
-    if package_file.checksum.is_some() {
-        let checksum = package_file.checksum.clone().unwrap();
-        let mut file = File::open(format!("{}/{}.star", config.tmp_dir, package)).unwrap();
+    install_plan(&config, &plan, None, &[])
+}
 
-        let mut buffer = Vec::new();
-        if file.read_to_end(&mut buffer).is_err() {
-            return Err(format!("Error while reading package file"));
-        }
 
-        let mut hasher = Sha256::new();
-        hasher.update(buffer);
+/// Downloads a package from a repository and stores it in the temp directory
+///
+/// The star is checked against its checksum (see `ChecksumPolicy`) and signature. If its galaxy
+/// has mirrors, they are tried in turn until one serves a good star.
+///
+/// # Arguments
+/// * `package` - The name of the package to download
+///
+/// # Returns
+/// * `Result<String>` - The mirror the star was downloaded from
+pub fn download_package(package: String) -> Result<String> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
 
-        let hash = format!("{:x}", hasher.finalize());
-        if hash != checksum {
-            return Err(format!("Checksum mismatch for package {}", package));
-        }
-    }
+    let (_, _, mirror) = fetch_star(&config, package)?;
 
-    Ok(())
+    Ok(mirror)
 }
 
-/// Finds a package file from a repository
+/// Downloads a star to the temp directory and verifies it
 ///
 /// # Arguments
-/// * `package` - The name of the package to find
+/// * `config` - The config to download under
+/// * `package` - The name of the package to download
 ///
 /// # Returns
-/// * `Option<String>` - The URL of the repository that contains the package
-/// * `Option<String>` - None if the package can't be found
-fn find_package_file_from_repo(package: String) -> Option<String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
+/// * `Result<(String, String, String)>` - The path of the star, its SHA-256 and the mirror it came from,
+///   or the package cache directory if it was already there
+fn fetch_star(config: &Config, package: String) -> Result<(String, String, String)> {
+    // load the cache
+    let cache = load_available(config)?;
+
+    // grab the package from the cache
+    let package_file = match cache.get_package(package.clone()) {
+        Some(package_file) => package_file,
+        None => return Err(Error::PackageNotFound(package)),
+    };
+
+    // the cache knows which galaxy has the star, so there's no need to ask every galaxy again
+    let galaxy = match &package_file.galaxy {
+        Some(galaxy) => galaxy,
+        None => {
+            return Err(Error::Parse {
+                context: format!("The cache entry for {} doesn't say which galaxy it came from, run update-cache to refresh it", package),
+                source: None,
+            });
         }
     };
 
-    for repository in config.repositories {
-        let repo = Repository::from_web(repository.clone());
+    transport::configure(config)?;
 
-        let package_file = repo.get_package(package.clone());
+    let mirrors = match galaxy::find(&config.repositories, package_file) {
+        Some(galaxy) => galaxy.urls(),
+        None => vec![galaxy.clone()],
+    };
 
-        if package_file.is_some() {
-            return Some(repository.clone());
-        }
-    }
+    let star = package_file.star.clone().unwrap_or_else(|| format!("{}.star", package));
 
-    None
-}
+    // stars with a known checksum go to the package cache, so reinstalls and downgrades can reuse them
+    let file_path = match package_cache::file_name(package_file) {
+        Some(file_name) => {
+            let cache_dir = config.package_cache_path();
+            std::fs::create_dir_all(&cache_dir)
+                .map_err(|err| Error::io(format!("Error while creating package cache {}", cache_dir), err))?;
 
-/// Updates the local cache of packages by downloading the repository files from the repositories
-pub fn update_cache_file() -> Result<(), String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(format!("Error while reading config file: {}", err));
+            let file_path = format!("{}/{}", cache_dir, file_name);
+
+            if Path::new(&file_path).exists() {
+                let digest = hash_file(&file_path)?;
+
+                if package_file.checksum.as_deref().map(|checksum| checksum.eq_ignore_ascii_case(&digest)).unwrap_or(false) {
+                    return Ok((file_path, digest, cache_dir));
+                }
+
+                // damaged somehow, so download it again
+                std::fs::remove_file(&file_path)
+                    .map_err(|err| Error::io(format!("Error while removing {}", file_path), err))?;
+            }
+
+            file_path
         }
+        None => format!("{}/{}.star", config.tmp_path(), package),
     };
 
-    let cache_file = format!("{}/cache.yml", config.storage_dir);
+    // the star is signed on its own, so a tampered repo.yml can't vouch for it
+    let keys = TrustedKeys::from_dir(&config.trusted_keys_path())?;
 
-    // remove the old cache file
-    if Path::new(&cache_file).exists() {
-        std::fs::remove_file(cache_file.clone()).unwrap();
-    }
+    // a mirror serving a bad star is skipped just like one that is down
+    let (digest, mirror) = transport::with_mirrors(&star, &mirrors, config.retries, |mirror| {
+        let url = transport::join(mirror, &star);
+        let digest = transport::download(&url, &file_path, &mut |done, total| report_progress(&package, done, total))?;
+
+        // check the checksum, which a remote star has to have unless the policy says otherwise
+        if let Err(err) = verify_checksum(config, &package, &digest, package_file.checksum.clone(), true) {
+            // don't leave a bad star around for the next attempt
+            let _ = std::fs::remove_file(&file_path);
+            return Err(err);
+        }
 
-    let cache = Repository::new(true);
+        if !keys.is_empty() {
+            let signature = transport::fetch(&format!("{}.sig", url)).map_err(|err| Error::Signature {
+                subject: url.clone(),
+                message: format!("the signature could not be downloaded ({})", err),
+            })?;
+            verify_star(&keys, &file_path, &String::from_utf8_lossy(&signature))?;
+        }
 
-    for repository in config.repositories {
-        let mut repo = Repository::from_string(repository.clone());
-        let repo_file = Repository::from_web(repository.clone());
+        Ok(digest)
+    })?;
 
-        for (name, value) in repo_file.packages {
-            if !repo.packages.contains_key(&name) {
-                repo.add_package(value);
-            } else {
-                // see if the version is greater than the one in the repo
-                let repo_package = repo.get_package(name.clone()).unwrap();
-                let repo_version = SemVer::from_string(repo_package.version.clone());
-                let value_version = SemVer::from_string(value.version.clone());
+    Ok((file_path, digest, mirror))
+}
 
-                if value_version > repo_version {
-                    repo.add_package(value);
-                }
+/// Checks a star against the checksum it is supposed to have, following the checksum policy
+///
+/// # Arguments
+/// * `config` - The config holding the checksum policy
+/// * `package` - The name of the package, used in errors
+/// * `hash` - The SHA-256 of the star
+/// * `expected` - The checksum the star should have, if one is known
+/// * `required` - Whether or not a missing checksum is a problem
+fn verify_checksum(config: &Config, package: &str, hash: &str, expected: Option<String>, required: bool) -> Result<()> {
+    let problem = match expected {
+        Some(expected) if !expected.eq_ignore_ascii_case(hash) => Some(format!("checksum mismatch, expected {} but got {}", expected, hash)),
+        Some(_) => None,
+        None if required => Some("no checksum is known for it".to_string()),
+        None => None,
+    };
+
+    if let Some(message) = problem {
+        match config.checksum_policy {
+            ChecksumPolicy::Require => {
+                return Err(Error::Integrity { package: package.to_string(), message });
             }
+            ChecksumPolicy::Warn => report_warning(&format!("integrity check failed for {}: {}", package, message)),
+            ChecksumPolicy::Off => {}
         }
     }
 
-    cache.to_file(format!("{}/cache.yml", config.storage_dir));
-
     Ok(())
 }
 
-/// Checks if a package is cached by looking in the cache file
-///
-/// If it is, it returns true
-///
-/// If it isn't, it returns false
+/// Calculates the SHA-256 of a file
 ///
 /// # Arguments
-/// * `package` - The name of the package to check
-///
-/// # Returns
-/// * `bool` - Whether or not the package is cached
-fn package_is_cached(package: String) -> bool {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
-    };
+/// * `file_path` - The file to hash
+fn hash_file(file_path: &str) -> Result<String> {
+    let mut file = File::open(file_path)
+        .map_err(|err| Error::io(format!("Error while opening {}", file_path), err))?;
 
-    let cache_file = format!("{}/cache.yml", config.storage_dir);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .map_err(|err| Error::io(format!("Error while reading {}", file_path), err))?;
 
-    let cache = Repository::from_file(cache_file);
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
-    let package_file = cache.get_package(package);
+/// Reads the checksum from a `<star>.sha256` file next to a local star, in `sha256sum` format
+///
+/// # Arguments
+/// * `star` - The path of the local star
+fn read_sidecar_checksum(star: &str) -> Result<Option<String>> {
+    let sidecar = format!("{}.sha256", star);
 
-    if package_file.is_none() {
-        return false;
+    if !Path::new(&sidecar).exists() {
+        return Ok(None);
     }
 
-    true
+    let contents = std::fs::read_to_string(&sidecar)
+        .map_err(|err| Error::io(format!("Error while reading {}", sidecar), err))?;
+
+    // "<hash>  <file name>", or just the hash
+    match contents.split_whitespace().next() {
+        Some(checksum) => Ok(Some(checksum.to_string())),
+        None => Err(Error::Parse {
+            context: format!("Checksum file {} is empty", sidecar),
+            source: None,
+        }),
+    }
 }
 
-/// Uses package_is_cached to find out if a dependency with the minimum version can be found
-/// in any of the repositories
-///
-/// If it can't, it returns an error
+/// Checks a star file against a detached signature
 ///
 /// # Arguments
-/// * `dependency` - The name of the dependency to check
-/// * `minimum_version` - The minimum version of the dependency to check
+/// * `keys` - The trusted keys
+/// * `file_path` - The star file
+/// * `signature` - The contents of the signature file
+fn verify_star(keys: &TrustedKeys, file_path: &str, signature: &str) -> Result<()> {
+    let star = File::open(file_path)
+        .map_err(|err| Error::io(format!("Error while opening {}", file_path), err))?;
+
+    keys.verify(file_path, std::io::BufReader::new(star), signature)
+}
+
+/// Updates the local cache of packages by downloading the repository files from the repositories
+///
+/// Every enabled galaxy is fetched and merged following the precedence rules of `Galaxy`, and
+/// packages a galaxy's include and exclude lists reject are left out. Every cached package records
+/// the galaxy it came from.
+///
+/// The last repo.yml of every galaxy is kept (see `GalaxyIndex`), and the server is asked whether it
+/// changed before downloading it again. A galaxy that can't be fetched doesn't fail the update.
+/// Whatever was fetched from it before is kept, and it is listed in `CacheUpdate::failed`.
 ///
 /// # Returns
-/// * `Result<(), String>` - An error if the dependency can't be found
-/// * `Result<(), String>` - Ok if the dependency can be found
-fn check_dependency(dependency: String, minimum_version: String) -> Result<(), String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
-    };
+/// * `Result<CacheUpdate>` - Which galaxies changed, which didn't and which failed
+pub fn update_cache_file() -> Result<CacheUpdate> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
 
-    let mut found = false;
+    let cache_file = format!("{}/cache.yml", config.storage_path());
 
-    // first see if it is installed
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let repo = Repository::from_file(repo_file);
-    let package = repo.get_package(dependency.clone());
-    if package.is_some() {
-        let package = package.unwrap();
-        let package_version = SemVer::from_string(package.version.clone());
-        let minimum_version = SemVer::from_string(minimum_version.clone());
+    // the old cache is only needed for galaxies that fail, so a broken one isn't fatal
+    let old_cache = Repository::from_file(cache_file.clone()).unwrap_or_else(|_| Repository::new(true));
 
-        if package_version >= minimum_version {
-            return Ok(());
-        }
-    }
+    let mut cache = Repository::new(true);
+    let mut update = CacheUpdate { updated: Vec::new(), unchanged: Vec::new(), failed: Vec::new() };
+    let keys = TrustedKeys::from_dir(&config.trusted_keys_path())?;
+    transport::configure(&config)?;
+
+    for galaxy in config.repositories.iter().filter(|galaxy| galaxy.enabled) {
+        let index_file = GalaxyIndex::path(&config.storage_path(), galaxy);
+
+        // an index checked against other keys than the ones trusted now can't be used again
+        let previous = GalaxyIndex::from_file(&index_file).filter(|index| index.keys == keys.fingerprint());
+
+        let fetched = transport::with_mirrors(&format!("the repo.yml of {}", galaxy.name), &galaxy.urls(), config.retries, |mirror| {
+            GalaxyIndex::fetch(mirror, &keys, previous.as_ref())
+        });
+
+        let packages: Vec<Package> = match (fetched, previous) {
+            (Ok((Some(index), mirror)), previous) => {
+                let changed = previous.map(|previous| previous.checksum != index.checksum).unwrap_or(true);
+
+                if changed {
+                    update.updated.push((galaxy.name.clone(), mirror));
+                } else {
+                    update.unchanged.push((galaxy.name.clone(), mirror));
+                }
+
+                index.to_file(&index_file)?;
+                index.repository.packages.into_values().collect()
+            }
+            // the server only says "not modified" to the mirror the stored index came from
+            (Ok((None, mirror)), Some(previous)) => {
+                update.unchanged.push((galaxy.name.clone(), mirror));
+                previous.repository.packages.into_values().collect()
+            }
+            (Ok((None, mirror)), None) => {
+                update.failed.push((galaxy.name.clone(), Error::Parse {
+                    context: format!("{} answered 304 Not Modified to an unconditional request", mirror),
+                    source: None,
+                }));
+                Vec::new()
+            }
+            (Err(err), previous) => {
+                update.failed.push((galaxy.name.clone(), err));
+
+                // keep what we already had from this galaxy
+                match previous {
+                    Some(previous) => previous.repository.packages.into_values().collect(),
+                    None => old_cache.packages.values()
+                        .filter(|package| package.galaxy.as_deref() == Some(galaxy.url.as_str()))
+                        .cloned()
+                        .collect(),
+                }
+            }
+        };
+
+        let packages = packages.into_iter().map(|mut package| {
+            // remember where the star is, so downloading it doesn't need this repo.yml again
+            package.star.get_or_insert_with(|| format!("{}.star", package.name));
+            package.galaxy = Some(galaxy.url.clone());
+            package
+        });
+
+        for package in packages {
+            if !galaxy.allows(&package.name) {
+                continue;
+            }
 
-    // if it isn't installed, see if it is cached and if it is, check the version
-    // if the version is greater than or equal to the minimum version, install it then return
-    // ok
-    if package_is_cached(dependency.clone()) {
-        let cache_file = format!("{}/cache.yml", config.storage_dir);
-        let cache = Repository::from_file(cache_file);
-        let package = cache.get_package(dependency.clone()).unwrap();
-        let package_version = SemVer::from_string(package.version.clone());
-        let minimum_version = SemVer::from_string(minimum_version.clone());
+            let replace = match cache.get_package(package.name.clone()) {
+                Some(existing) => galaxy::prefer(&config.repositories, existing, &package),
+                None => true,
+            };
 
-        if package_version >= minimum_version {
-            found = true;
+            if replace {
+                cache.add_package(package);
+            }
         }
     }
 
-    // if it isn't installed or cached, then as far as we know it doesn't exist so return an error
-    if !found {
-        return Err(format!("Dependency {} not found", dependency));
-    }
+    // written to a temporary file and renamed, so readers never see half a cache
+    cache.to_file(cache_file)?;
 
-    // if its cached but not installed, install it (or at least try to)
-    if install_package(dependency.clone(), false, false).is_err() {
-        return Err(format!("Dependency {} could not be installed", dependency));
-    }
+    Ok(update)
+}
 
-    Ok(())
+/// Removes every star from the package cache
+///
+/// # Returns
+/// * `Result<CacheUsage>` - How many stars were removed and how much space that freed
+pub fn clean_package_cache() -> Result<CacheUsage> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
+
+    package_cache::clean(&config.package_cache_path())
 }
 
-/// Sets up the system by creating the config file and storage directory
-pub fn setup() -> Result<(), String>{
-    // first, create the containing directory
-    let config_location;
+/// Removes the stars from the package cache that can't be downloaded anymore
+///
+/// Stars are kept as long as the cache lists the same name, version and checksum, so run
+/// `update_cache_file` first to find out what the galaxies offer now.
+///
+/// # Returns
+/// * `Result<CacheUsage>` - How many stars were removed and how much space that freed
+pub fn autoclean_package_cache() -> Result<CacheUsage> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
 
-    // if linux
-    #[cfg(target_os = "linux")]
-    {
-        config_location = "/etc/comet".to_string();
-    }
+    let available = load_available(&config)?;
 
-    // if windows
-    #[cfg(target_os = "windows")]
-    {
-        config_location = "C:\\Program Files\\Comet".to_string();
-    }
+    package_cache::autoclean(&config.package_cache_path(), &available)
+}
 
-    // if mac
-    #[cfg(target_os = "macos")]
-    {
-        config_location = "/Library/Application Support/Comet".to_string();
-    }
+/// Gets how many stars are in the package cache and how much space they take up
+pub fn package_cache_usage() -> Result<CacheUsage> {
+    let config = Config::from_file()?;
+    let _lock = Lock::shared(&config)?;
 
-    // if the directory doesn't exist, create it
-    if !Path::new(&config_location).exists() {
-        if std::fs::create_dir_all(config_location.clone()).is_err() {
-            return Err(format!("Error while creating config directory: {}", config_location));
-        }
-    }
+    package_cache::usage(&config.package_cache_path())
+}
 
-    // now create the config file
-    let config_location;
+/// Loads the cached packages that the galaxy config allows (see `galaxy::filter`)
+///
+/// # Arguments
+/// * `config` - The config holding the galaxies
+fn load_available(config: &Config) -> Result<Repository> {
+    let cache_file = format!("{}/cache.yml", config.storage_path());
+    let mut available = Repository::from_file(cache_file)?;
 
-    // if linux
-    #[cfg(target_os = "linux")]
-    {
-        config_location = "/etc/comet/config.yml".to_string();
-    }
+    galaxy::filter(&config.repositories, &mut available);
 
-    // if windows
-    #[cfg(target_os = "windows")]
-    {
-        config_location = "C:\\Program Files\\Comet\\config.yml".to_string();
-    }
+    Ok(available)
+}
 
-    // if mac
-    #[cfg(target_os = "macos")]
-    {
-        config_location = "/Library/Application Support/Comet/config.yml".to_string();
-    }
+/// Sets up the system by creating the config file and storage directory
+///
+/// Everything is created under the install root (see `set_install_root`)
+pub fn setup() -> Result<()> {
+    let root = install_root();
 
-    let mut config = Config::new(vec![], false, "".to_string(), "".to_string());
+    // first, create the containing directory
+    let config_location = config::config_dir(&root);
 
-    // if the config file doesn't exist, create it
+    // if the directory doesn't exist, create it
     if !Path::new(&config_location).exists() {
-        if std::fs::create_dir_all(config_location.clone()).is_err() {
-            return Err(format!("Error while creating config file: {}", config_location));
-        }
-
-        // if linux
-        #[cfg(target_os = "linux")]
-        {
-            config = Config::new(vec!["https://repo.wombatlinux.org".to_string()], false, "/var/lib/comet".to_string(), "/tmp".to_string());
-        }
+        std::fs::create_dir_all(config_location.clone())
+            .map_err(|err| Error::io(format!("Error while creating config directory {}", config_location), err))?;
+    }
 
-        // if windows
-        #[cfg(target_os = "windows")]
-        {
-            config = Config::new(vec!["https://repo.wombatlinux.org".to_string()], false, "C:\\Program Files\\Comet".to_string(), "C:\\Windows\\Temp".to_string());
-        }
+    // now create the config file
+    let config_location = config::config_file(&root);
 
-        // if mac
-        #[cfg(target_os = "macos")]
-        {
-            config = Config::new(vec!["https://repo.wombatlinux.org".to_string()], false, "/Library/Application Support/Comet".to_string(), "/tmp".to_string());
-        }
+    // if the config file doesn't exist, create it
+    if !Path::new(&config_location).exists() {
+        let config = Config::default_for_platform();
 
         // write the config to the file
-
-        if std::fs::write(config_location.clone(), config.to_string()).is_err() {
-            return Err(format!("Error while writing config file: {}", config_location));
-        }
+        std::fs::write(config_location.clone(), config.to_string())
+            .map_err(|err| Error::io(format!("Error while writing config file {}", config_location), err))?;
     }
 
-    // create the storage directory and tmp directory if they don't exist
     // use the config file to get the storage directory and tmp directory
+    let config = Config::from_root(root)?;
 
-    if !Path::new(&config.tmp_dir.clone()).exists() {
-        if std::fs::create_dir_all(config.tmp_dir.clone()).is_err() {
-            return Err(format!("Error while creating temp directory: {}", config.tmp_dir));
-        }
+    // create the storage directory and tmp directory if they don't exist
+    if !Path::new(&config.tmp_path()).exists() {
+        std::fs::create_dir_all(config.tmp_path())
+            .map_err(|err| Error::io(format!("Error while creating temp directory {}", config.tmp_path()), err))?;
     }
 
-    if !Path::new(&config.storage_dir.clone()).exists() {
-        if std::fs::create_dir_all(config.storage_dir.clone()) .is_err() {
-            return Err(format!("Error while creating storage directory: {}", config.storage_dir));
-        }
+    if !Path::new(&config.storage_path()).exists() {
+        std::fs::create_dir_all(config.storage_path())
+            .map_err(|err| Error::io(format!("Error while creating storage directory {}", config.storage_path()), err))?;
     }
 
+    // the database files are created under the lock like everything else that changes them
+    let _lock = Lock::exclusive(&config)?;
+
     // only create the cache file if it doesn't exist
-    let cache_file = format!("{}/cache.yml", config.storage_dir.clone());
+    let cache_file = format!("{}/cache.yml", config.storage_path());
     if !Path::new(&cache_file).exists() {
         let cache = Repository::new(true);
-        cache.to_file(cache_file.clone());
+        cache.to_file(cache_file.clone())?;
     }
 
     // same with the repo file
-    let repo_file = format!("{}/repo.yml", config.storage_dir.clone());
+    let repo_file = format!("{}/repo.yml", config.storage_path());
     if !Path::new(&repo_file).exists() {
         let repo = Repository::new(false);
-        repo.to_file(repo_file.clone());
+        repo.to_file(repo_file.clone())?;
     }
 
     Ok(())
 }
 
+/// Sets the install root that every comet path is resolved under
+///
+/// This allows comet to operate on a chroot or image directory instead of the running system.
+/// The config file, `repo.yml`, `cache.yml`, scripts and temp files are all read from and written
+/// to the root, and install/remove scripts get it in the `COMET_ROOT` environment variable.
+///
+/// # Arguments
+/// * `root` - The directory to use as the root, or an empty string for the running system
+pub fn set_install_root(root: String) {
+    let mut current = INSTALL_ROOT.write().unwrap_or_else(|err| err.into_inner());
+    *current = root.trim_end_matches('/').to_string();
+}
+
+/// Gets the install root set by `set_install_root`
+///
+/// # Returns
+/// * `String` - The install root, or an empty string for the running system
+pub fn install_root() -> String {
+    INSTALL_ROOT.read().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+/// Sets a callback that is told how far along every star download is
+///
+/// The callback may be called from several threads at once and should return quickly.
+///
+/// # Arguments
+/// * `callback` - The callback, or `None` to stop reporting progress
+pub fn set_progress_callback(callback: Option<ProgressCallback>) {
+    let mut current = PROGRESS_CALLBACK.write().unwrap_or_else(|err| err.into_inner());
+    *current = callback;
+}
+
+/// Passes the progress of a download to the callback set by `set_progress_callback`, if any
+fn report_progress(package: &str, done: u64, total: Option<u64>) {
+    let callback = PROGRESS_CALLBACK.read().unwrap_or_else(|err| err.into_inner());
+
+    if let Some(callback) = callback.as_ref() {
+        callback(package, done, total);
+    }
+}
+
+/// Sets a callback that is told about problems comet carries on despite
+///
+/// Without a callback those problems go unreported. The callback may be called from several
+/// threads at once.
+///
+/// # Arguments
+/// * `callback` - The callback, or `None` to stop reporting warnings
+pub fn set_warning_callback(callback: Option<WarningCallback>) {
+    let mut current = WARNING_CALLBACK.write().unwrap_or_else(|err| err.into_inner());
+    *current = callback;
+}
+
+/// Passes a warning to the callback set by `set_warning_callback`, if any
+fn report_warning(message: &str) {
+    let callback = WARNING_CALLBACK.read().unwrap_or_else(|err| err.into_inner());
+
+    if let Some(callback) = callback.as_ref() {
+        callback(message);
+    }
+}
+
+/// Runs a package script with `sh`, exporting the install root to it
+///
+/// # Arguments
+/// * `config` - The config the script is run under
+/// * `script` - The path to the script
+/// * `working_dir` - The directory to run the script in
+fn run_script(config: &Config, script: String, working_dir: String) -> Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg(&script)
+        .current_dir(working_dir)
+        .env("COMET_ROOT", config.root_path())
+        .status();
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::Script {
+            script,
+            message: format!("failed with {}", status),
+            source: None,
+        }),
+        Err(err) => Err(Error::Script {
+            script,
+            message: "could not be run".to_string(),
+            source: Some(err),
+        }),
+    }
+}
 
 /// Lists all installed packages
 ///
 /// # Returns
 /// * `HashMap<String, String>` - A hashmap of package name to version
-pub fn list_packages() -> HashMap<String, String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
-    };
+pub fn list_packages() -> Result<HashMap<String, String>> {
+    let config = Config::from_file()?;
+    let _lock = Lock::shared(&config)?;
 
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let repo = Repository::from_file(repo_file);
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let repo = Repository::from_file(repo_file)?;
 
     let mut packages = HashMap::new();
 
     for (_, package) in repo.packages {
-        packages.insert(package.name, package.version);
+        packages.insert(package.name.clone(), package.display_version());
     }
 
-    packages
+    Ok(packages)
 }
 
 /// Lists all available packages
 ///
 /// # Returns
 /// * `HashMap<String, String>` - A hashmap of package name to version
-pub fn list_available_packages() -> HashMap<String, String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
-    };
+pub fn list_available_packages() -> Result<HashMap<String, String>> {
+    let config = Config::from_file()?;
+    let _lock = Lock::shared(&config)?;
 
-    let cache_file = format!("{}/cache.yml", config.storage_dir);
-    let cache = Repository::from_file(cache_file);
+    let cache = load_available(&config)?;
 
     let mut packages = HashMap::new();
 
     for (_, package) in cache.packages {
-        packages.insert(package.name, package.version);
+        packages.insert(package.name.clone(), package.display_version());
     }
 
-    packages
+    Ok(packages)
 }
 
 
 /// Updates all packages
 ///
 /// Basically just runs update_package on all installed packages
-pub fn update_all_packages()  {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
-    };
+///
+/// # Returns
+/// * `Result<Vec<(String, String)>>` - The stars that were fetched, with the mirror each one came from
+pub fn update_all_packages() -> Result<Vec<(String, String)>> {
+    let config = Config::from_file()?;
+    let _lock = Lock::exclusive(&config)?;
 
-    let repo_file = format!("{}/repo.yml", config.storage_dir);
-    let repo = Repository::from_file(repo_file);
+    let repo_file = format!("{}/repo.yml", config.storage_path());
+    let repo = Repository::from_file(repo_file)?;
 
-    for (_, package) in repo.packages {
-        let _ = update_package(package.name.clone());
+    let mut downloads = Vec::new();
 
+    for (_, package) in repo.packages {
         // we don't care if it fails, we just want to try to update all packages
+        if let Ok(fetched) = update_package(package.name.clone()) {
+            downloads.extend(fetched);
+        }
     }
+
+    Ok(downloads)
 }
 
-pub fn check_permissions() -> bool {
+pub fn check_permissions() -> Result<bool> {
     // make sure the correct directories can be accessed with write permissions
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
-    };
+    let config = Config::from_file()?;
 
     let mut can_write = true;
 
-    let locations = vec![config.storage_dir.clone(), config.tmp_dir.clone()];
+    let locations = vec![config.storage_path(), config.tmp_path()];
 
     for location in locations {
         // check if readonly
-        let metadata = std::fs::metadata(location.clone()).unwrap();
+        let metadata = std::fs::metadata(location.clone())
+            .map_err(|err| Error::io(format!("Error while reading {}", location), err))?;
         let readonly = metadata.permissions().readonly();
 
         if readonly {
@@ -696,36 +1352,72 @@ pub fn check_permissions() -> bool {
         }
     }
 
-    can_write
+    Ok(can_write)
 }
 
-pub fn get_package_details(package: String) -> Option<String> {
-    let config = match Config::from_file() {
-        Ok(config) => config,
-        Err(err) => {
-            panic!("Error while reading config file: {}", err);
-        }
+/// Describes an available package, including what it depends on, recommends and suggests
+///
+/// # Returns
+/// * `Result<Option<String>>` - The details, or None if the package isn't in the cache
+pub fn get_package_details(package: String) -> Result<Option<String>> {
+    let config = Config::from_file()?;
+    let _lock = Lock::shared(&config)?;
+
+    let repo_file = format!("{}/cache.yml", config.storage_path());
+    let repo = Repository::from_file(repo_file)?;
+
+    let package_file = match repo.get_package(package) {
+        Some(package_file) => package_file,
+        None => return Ok(None),
     };
 
-    let repo_file = format!("{}/cache.yml", config.storage_dir);
-    let repo = Repository::from_file(repo_file);
+    // lets format the package details
+    let mut details = format!("{} - {}\n\n{}\n{}", package_file.name, package_file.display_version(),
+                              package_file.description, package_file.license);
+
+    let relations = [
+        ("Depends", &package_file.dependencies),
+        ("Recommends", &package_file.recommends),
+        ("Suggests", &package_file.suggests),
+    ];
+
+    for (title, packages) in relations {
+        if packages.is_empty() {
+            continue;
+        }
 
-    let package_file = repo.get_package(package);
+        let mut packages: Vec<String> = packages.iter().map(|(name, constraint)| format!("{} ({})", name, constraint)).collect();
+        packages.sort();
 
-    if package_file.is_none() {
+        details.push_str(&format!("\n{}: {}", title, packages.join(", ")));
+    }
+
+    Ok(Some(details))
+}
+
+/// Converts a string handed in from C, returning None if it is null or not valid UTF-8
+///
+/// # Safety
+/// `string` must be null or point to a nul-terminated string
+unsafe fn from_c_string(string: *const c_char) -> Option<String> {
+    if string.is_null() {
         return None;
     }
 
-    let package_file = package_file.unwrap();
+    let string = unsafe { std::ffi::CStr::from_ptr(string) };
 
-    // lets format the package details
-    let details = format!("{} - {}\n\n{}\n{}", package_file.name, package_file.version,
-                          package_file.description, package_file.license);
+    string.to_str().ok().map(|string| string.to_string())
+}
 
-    Some(details)
+/// Converts a string to hand out to C, returning null if it contains a nul byte
+fn to_c_string(string: String) -> *const c_char {
+    match std::ffi::CString::new(string) {
+        Ok(c_string) => c_string.into_raw(),
+        Err(_) => std::ptr::null(),
+    }
 }
 
-/**
+/*
 BEGIN C COMPATIBLE FUNCTIONS FOR USE IN OTHER LANGUAGES (C, C++, Python, etc.)
 
 These functions are used to make comet usable in other languages, such as if you want to use
@@ -735,72 +1427,75 @@ If you are using comet in a Rust application, you should use the functions above
 functions, as they are more idiomatic Rust code and are easier to use in Rust applications than
 these functions are (since they are written in Rust, for Rust).
 **/
-
+/// # Safety
+/// `package` must be null or point to a nul-terminated string
 #[no_mangle]
-pub extern "C" fn install(package: *const c_char, local: bool, force: bool) -> bool {
-    let package = unsafe {
-        assert!(!package.is_null());
-
-        std::ffi::CStr::from_ptr(package)
+pub unsafe extern "C" fn install(package: *const c_char, local: bool, force: bool) -> bool {
+    let package = match from_c_string(package) {
+        Some(package) => package,
+        None => return false,
     };
 
-    let package = package.to_str().unwrap();
-
-    let result = install_package(package.to_string(), local, force);
-
-    if result.is_err() {
-        return false;
-    }
-
-    true
+    install_package(package, local, force, true).is_ok()
 }
 
+/// # Safety
+/// `package` must be null or point to a nul-terminated string
 #[no_mangle]
-pub extern "C" fn remove(package: *const c_char, force: bool) -> bool {
-    let package = unsafe {
-        assert!(!package.is_null());
-
-        std::ffi::CStr::from_ptr(package)
+pub unsafe extern "C" fn remove(package: *const c_char, force: bool) -> bool {
+    let package = match from_c_string(package) {
+        Some(package) => package,
+        None => return false,
     };
 
-    let package = package.to_str().unwrap();
-
-    let result = remove_package(package.to_string(), force);
+    remove_package(package, force).is_ok()
+}
 
-    if result.is_err() {
-        return false;
-    }
+/// # Safety
+/// `package` must be null or point to a nul-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn update(package: *const c_char) -> bool {
+    let package = match from_c_string(package) {
+        Some(package) => package,
+        None => return false,
+    };
 
-    true
+    update_package(package).is_ok()
 }
 
+/// # Safety
+/// `package` must be null or point to a nul-terminated string
 #[no_mangle]
-pub extern "C" fn update(package: *const c_char) -> bool {
-    let package = unsafe {
-        assert!(!package.is_null());
-
-        std::ffi::CStr::from_ptr(package)
+pub unsafe extern "C" fn mark(package: *const c_char, dependency: bool) -> bool {
+    let package = match from_c_string(package) {
+        Some(package) => package,
+        None => return false,
     };
 
-    let package = package.to_str().unwrap();
-
-    let result = update_package(package.to_string());
+    let reason = match dependency {
+        true => InstallReason::Dependency,
+        false => InstallReason::Explicit,
+    };
 
-    if result.is_err() {
-        return false;
-    }
+    mark_package(package, reason).is_ok()
+}
 
-    true
+#[no_mangle]
+pub extern "C" fn autoremove() -> bool {
+    autoremove_packages().is_ok()
 }
 
 #[no_mangle]
 pub extern "C" fn update_all() {
-    update_all_packages();
+    let _ = update_all_packages();
 }
 
 #[no_mangle]
 pub extern "C" fn list() -> *const c_char {
-    let packages = list_packages();
+    let packages = match list_packages() {
+        Ok(packages) => packages,
+        Err(_) => return std::ptr::null(),
+    };
 
     let mut string = String::new();
 
@@ -808,14 +1503,15 @@ pub extern "C" fn list() -> *const c_char {
         string.push_str(&format!("{}: {}\n", key, value));
     }
 
-    let c_string = std::ffi::CString::new(string).unwrap();
-
-    c_string.into_raw()
+    to_c_string(string)
 }
 
 #[no_mangle]
 pub extern "C" fn list_available() -> *const c_char {
-    let packages = list_available_packages();
+    let packages = match list_available_packages() {
+        Ok(packages) => packages,
+        Err(_) => return std::ptr::null(),
+    };
 
     let mut string = String::new();
 
@@ -823,57 +1519,158 @@ pub extern "C" fn list_available() -> *const c_char {
         string.push_str(&format!("{}: {}\n", key, value));
     }
 
-    let c_string = std::ffi::CString::new(string).unwrap();
-
-    c_string.into_raw()
+    to_c_string(string)
 }
 
 #[no_mangle]
 pub extern "C" fn update_cache() -> bool {
-    let result = update_cache_file();
-
-    if result.is_err() {
-        return false;
+    match update_cache_file() {
+        Ok(update) => update.failed.is_empty(),
+        Err(_) => false,
     }
+}
 
-    true
+#[no_mangle]
+pub extern "C" fn clean_cache() -> bool {
+    clean_package_cache().is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn autoclean_cache() -> bool {
+    autoclean_package_cache().is_ok()
 }
 
 #[no_mangle]
 pub extern "C" fn check_perms() -> bool {
-    check_permissions()
+    check_permissions().unwrap_or(false)
 }
 
 #[no_mangle]
 pub extern "C" fn setup_comet() -> bool {
-    let result = setup();
+    setup().is_ok()
+}
 
-    if result.is_err() {
-        return false;
-    }
+/// # Safety
+/// `root` must be null or point to a nul-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn set_root(root: *const c_char) -> bool {
+    let root = match from_c_string(root) {
+        Some(root) => root,
+        None => return false,
+    };
+
+    set_install_root(root);
 
     true
 }
 
+/// # Safety
+/// `package` must be null or point to a nul-terminated string
 #[no_mangle]
-pub extern "C" fn package_details(package: *const c_char) -> *const c_char {
-    let package = unsafe {
-        assert!(!package.is_null());
-
-        std::ffi::CStr::from_ptr(package)
+pub unsafe extern "C" fn package_details(package: *const c_char) -> *const c_char {
+    let package = match from_c_string(package) {
+        Some(package) => package,
+        None => return std::ptr::null(),
     };
 
-    let package = package.to_str().unwrap();
+    match get_package_details(package) {
+        Ok(Some(details)) => to_c_string(details),
+        _ => std::ptr::null(),
+    }
+}
+
+/// Sets a callback for download progress, see `set_progress_callback`
+///
+/// The callback gets the name of the package, which is only valid during the call, the bytes
+/// downloaded so far and the size of the star, 0 if it isn't known. Null stops reporting progress.
+#[no_mangle]
+pub extern "C" fn set_progress(callback: Option<extern "C" fn(*const c_char, u64, u64)>) {
+    set_progress_callback(callback.map(|callback| -> ProgressCallback {
+        Box::new(move |package: &str, done: u64, total: Option<u64>| {
+            if let Ok(package) = std::ffi::CString::new(package) {
+                callback(package.as_ptr(), done, total.unwrap_or(0));
+            }
+        })
+    }));
+}
+
+/// Sets a callback for warnings, see `set_warning_callback`
+///
+/// The callback gets the warning, which is only valid during the call. Null stops reporting
+/// warnings.
+#[no_mangle]
+pub extern "C" fn set_warning(callback: Option<extern "C" fn(*const c_char)>) {
+    set_warning_callback(callback.map(|callback| -> WarningCallback {
+        Box::new(move |message: &str| {
+            if let Ok(message) = std::ffi::CString::new(message) {
+                callback(message.as_ptr());
+            }
+        })
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
 
-    let details = get_package_details(package.to_string());
+    fn config(checksum_policy: ChecksumPolicy) -> Config {
+        let mut config = Config::new(Vec::new(), false, String::new(), String::new());
+        config.checksum_policy = checksum_policy;
+        config
+    }
+
+    const HASH: &str = "5d442028fe0d3dc740c97557725af3eee77ec0e659d9b4b37c99e7a14a43cb2a";
+    const OTHER: &str = "37f49b15c2afb3f03d419740d2ed8e18176983afd11b35b9b98b4cc071127ed8";
+
+    #[test]
+    fn require_refuses_missing_and_mismatched_checksums() {
+        let config = config(ChecksumPolicy::Require);
+
+        verify_checksum(&config, "hello", HASH, Some(HASH.to_string()), true).unwrap();
+        verify_checksum(&config, "hello", HASH, Some(HASH.to_ascii_uppercase()), true).unwrap();
 
-    if details.is_none() {
-        return std::ptr::null();
+        assert!(matches!(verify_checksum(&config, "hello", HASH, Some(OTHER.to_string()), false), Err(Error::Integrity { .. })));
+        assert!(matches!(verify_checksum(&config, "hello", HASH, None, true), Err(Error::Integrity { .. })));
+
+        // local stars don't have to come with a checksum
+        verify_checksum(&config, "hello", HASH, None, false).unwrap();
     }
 
-    let details = details.unwrap();
+    #[test]
+    fn warn_reports_problems_and_carries_on() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = warnings.clone();
+
+        // the callback is global, so only warnings about this test's package count
+        set_warning_callback(Some(Box::new(move |message: &str| {
+            if message.contains("warn-test") {
+                seen.lock().unwrap().push(message.to_string());
+            }
+        })));
+
+        let config = config(ChecksumPolicy::Warn);
+
+        verify_checksum(&config, "warn-test", HASH, Some(HASH.to_string()), true).unwrap();
+        assert!(warnings.lock().unwrap().is_empty());
 
-    let c_string = std::ffi::CString::new(details).unwrap();
+        verify_checksum(&config, "warn-test", HASH, Some(OTHER.to_string()), true).unwrap();
+        verify_checksum(&config, "warn-test", HASH, None, true).unwrap();
+        verify_checksum(&config, "warn-test", HASH, None, false).unwrap();
 
-    c_string.into_raw()
+        set_warning_callback(None);
+
+        let warnings = warnings.lock().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("checksum mismatch"));
+        assert!(warnings[1].contains("no checksum is known"));
+    }
+
+    #[test]
+    fn off_checks_nothing() {
+        let config = config(ChecksumPolicy::Off);
+
+        verify_checksum(&config, "hello", HASH, Some(OTHER.to_string()), true).unwrap();
+        verify_checksum(&config, "hello", HASH, None, true).unwrap();
+    }
 }
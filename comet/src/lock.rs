@@ -0,0 +1,219 @@
+use std::cell::Cell;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::time::{Duration, Instant};
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+/// How long to sleep between attempts while waiting for the lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Taken by read-only functions, any number of processes can hold it at once
+    Shared,
+    /// Taken by functions that change the database, only one process can hold it
+    Exclusive,
+}
+
+thread_local! {
+    /// The lock this thread already holds, so entry points calling other entry points don't wait on themselves
+    static HELD: Cell<Option<LockKind>> = const { Cell::new(None) };
+}
+
+/// A lock on the comet database, released when dropped
+///
+/// The lock file lives at `<storage_dir>/comet.lock`. Whoever holds the exclusive lock writes its
+/// PID into it, so a process that has to wait can say who it is waiting for.
+pub struct Lock {
+    /// None if this thread already held the lock when it was taken
+    file: Option<File>,
+    kind: LockKind,
+}
+
+impl Lock {
+    /// Takes the shared lock, waiting up to `lock_timeout` seconds for an exclusive holder
+    pub fn shared(config: &Config) -> Result<Lock> {
+        Lock::acquire(config, LockKind::Shared)
+    }
+
+    /// Takes the exclusive lock, waiting up to `lock_timeout` seconds for every other holder
+    pub fn exclusive(config: &Config) -> Result<Lock> {
+        Lock::acquire(config, LockKind::Exclusive)
+    }
+
+    fn acquire(config: &Config, kind: LockKind) -> Result<Lock> {
+        let lock_file = format!("{}/comet.lock", config.storage_path());
+
+        match HELD.get() {
+            Some(LockKind::Exclusive) => return Ok(Lock { file: None, kind }),
+            Some(LockKind::Shared) if kind == LockKind::Shared => return Ok(Lock { file: None, kind }),
+            Some(LockKind::Shared) => {
+                return Err(Error::InvalidArgument(format!("Cannot take the exclusive lock on {} while holding the shared one", lock_file)));
+            }
+            None => {}
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_file)
+            .map_err(|err| Error::io(format!("Error while opening lock file {}", lock_file), err))?;
+
+        let deadline = Instant::now() + Duration::from_secs(config.lock_timeout);
+
+        loop {
+            let result = match kind {
+                LockKind::Shared => file.try_lock_shared(),
+                LockKind::Exclusive => file.try_lock(),
+            };
+
+            match result {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => std::thread::sleep(RETRY_INTERVAL),
+                Err(TryLockError::WouldBlock) => {
+                    return Err(Error::Locked { path: lock_file, pid: holder_pid(&mut file) });
+                }
+                Err(TryLockError::Error(err)) => {
+                    return Err(Error::io(format!("Error while locking {}", lock_file), err));
+                }
+            }
+        }
+
+        // let anyone waiting on us know who we are
+        if kind == LockKind::Exclusive {
+            let pid = std::process::id().to_string();
+
+            file.set_len(0)
+                .and_then(|_| file.rewind())
+                .and_then(|_| file.write_all(pid.as_bytes()))
+                .map_err(|err| Error::io(format!("Error while writing lock file {}", lock_file), err))?;
+        }
+
+        HELD.set(Some(kind));
+
+        Ok(Lock { file: Some(file), kind })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // only the outermost lock actually holds the file
+        if let Some(file) = self.file.take() {
+            if self.kind == LockKind::Exclusive {
+                let _ = file.set_len(0);
+            }
+
+            let _ = file.unlock();
+            HELD.set(None);
+        }
+    }
+}
+
+/// Reads the PID of the exclusive holder from the lock file, if there is one
+fn holder_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+
+    contents.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config for a temp storage directory, which gives up on a held lock after `lock_timeout` seconds
+    fn config(dir: &tempfile::TempDir, lock_timeout: u64) -> Config {
+        let mut config = Config::new(Vec::new(), false, dir.path().to_string_lossy().to_string(), String::new());
+        config.lock_timeout = lock_timeout;
+        config
+    }
+
+    /// Tries to take a lock from another thread, which doesn't share this thread's `HELD`
+    fn elsewhere(config: &Config, kind: LockKind) -> Result<()> {
+        let config = config.clone();
+
+        std::thread::spawn(move || Lock::acquire(&config, kind).map(|_| ())).join().unwrap()
+    }
+
+    #[test]
+    fn reentrant_acquire_does_not_wait_on_itself() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, 0);
+
+        let outer = Lock::exclusive(&config).unwrap();
+
+        // entry points that call other entry points take the lock again
+        drop(Lock::exclusive(&config).unwrap());
+        drop(Lock::shared(&config).unwrap());
+
+        // dropping the inner locks didn't release the outer one
+        assert!(matches!(elsewhere(&config, LockKind::Shared), Err(Error::Locked { .. })));
+
+        drop(outer);
+        elsewhere(&config, LockKind::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn shared_lock_cannot_be_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, 0);
+
+        let _shared = Lock::shared(&config).unwrap();
+        drop(Lock::shared(&config).unwrap());
+
+        assert!(matches!(Lock::exclusive(&config), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn exclusive_holder_is_named_to_whoever_waits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, 0);
+
+        let _lock = Lock::exclusive(&config).unwrap();
+
+        let Err(Error::Locked { pid, .. }) = elsewhere(&config, LockKind::Exclusive) else {
+            panic!("the exclusive lock was taken twice");
+        };
+
+        assert_eq!(pid, Some(std::process::id()));
+        assert!(matches!(elsewhere(&config, LockKind::Shared), Err(Error::Locked { .. })));
+    }
+
+    #[test]
+    fn shared_locks_only_keep_out_exclusive_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, 0);
+
+        let _lock = Lock::shared(&config).unwrap();
+
+        elsewhere(&config, LockKind::Shared).unwrap();
+        assert!(matches!(elsewhere(&config, LockKind::Exclusive), Err(Error::Locked { pid: None, .. })));
+    }
+
+    #[test]
+    fn waits_for_the_holder_until_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, 5);
+
+        let lock = Lock::exclusive(&config).unwrap();
+
+        let waiter = {
+            let config = config.clone();
+            std::thread::spawn(move || {
+                let started = Instant::now();
+                Lock::exclusive(&config).map(|_| started.elapsed())
+            })
+        };
+
+        std::thread::sleep(Duration::from_millis(300));
+        drop(lock);
+
+        let waited = waiter.join().unwrap().unwrap();
+        assert!(waited >= Duration::from_millis(300) && waited < Duration::from_secs(5));
+    }
+}
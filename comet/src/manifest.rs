@@ -0,0 +1,486 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::error::{Error, Result};
+use crate::transaction::Transaction;
+
+/// The kind of filesystem entry a manifest entry describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A single path a package put on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The absolute path on the target system (not prefixed with the install root)
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    /// The SHA-256 of the file contents, only set for regular files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The link target, only set for symlinks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// The owner and group of each payload path as recorded in the star, keyed by system path
+pub type Ownership = HashMap<String, (u32, u32)>;
+
+/// The list of every path a package owns, in the order they were deployed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub package: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(package: String) -> Manifest {
+        Manifest {
+            package,
+            files: Vec::new(),
+        }
+    }
+
+    pub fn from_file(path: String) -> Result<Manifest> {
+        let manifest = match std::fs::read_to_string(path.clone()) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                return Err(Error::io(format!("Error while reading manifest {}", path), err));
+            }
+        };
+
+        match serde_yaml::from_str(&manifest) {
+            Ok(manifest) => Ok(manifest),
+            Err(err) => Err(Error::parse(format!("Error while parsing manifest {}", path), err)),
+        }
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let manifest = serde_yaml::to_string(&self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", manifest)
+    }
+}
+
+/// Copies the `package/` payload of an extracted star into the install root
+///
+/// Modes, symlinks and ownership are preserved. Ownership is only applied when the process is
+/// allowed to change it, so unprivileged image builds still work.
+///
+/// Directories that already exist, like `/usr`, are left as they are and don't become part of the
+/// package, unless an earlier version of the package created them.
+///
+/// # Arguments
+/// * `package` - The name of the package being deployed
+/// * `source` - The extracted `package/` directory
+/// * `root` - The install root to deploy into, or an empty string for the running system
+/// * `ownership` - The owners recorded in the star, since extracting as a normal user loses them
+/// * `previous` - The manifest of the installed version, if this is an upgrade or reinstall
+/// * `transaction` - The transaction every change is recorded in
+///
+/// # Returns
+/// * `Result<Manifest>` - The manifest of every deployed path
+pub fn deploy(package: String, source: &Path, root: &str, ownership: &Ownership, previous: Option<&Manifest>, transaction: &mut Transaction) -> Result<Manifest> {
+    let mut manifest = Manifest::new(package);
+
+    let owned_dirs: HashSet<String> = previous.iter()
+        .flat_map(|previous| previous.files.iter())
+        .filter(|entry| entry.kind == EntryKind::Directory)
+        .map(|entry| entry.path.clone())
+        .collect();
+
+    if source.exists() {
+        deploy_dir(source, Path::new("/"), root, ownership, &owned_dirs, &mut manifest, transaction)?;
+    }
+
+    Ok(manifest)
+}
+
+fn deploy_dir(source: &Path, relative: &Path, root: &str, ownership: &Ownership, owned_dirs: &HashSet<String>, manifest: &mut Manifest, transaction: &mut Transaction) -> Result<()> {
+    let entries = match std::fs::read_dir(source) {
+        Ok(entries) => entries,
+        Err(err) => {
+            return Err(Error::io(format!("Error while reading directory {}", source.display()), err));
+        }
+    };
+
+    // sort the entries so the manifest is the same every time
+    let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let source_path = entry.path();
+        let system_path = relative.join(entry.file_name());
+        let target = crate::config::resolve_under(root, &system_path.to_string_lossy());
+        let target = Path::new(&target);
+
+        let metadata = match std::fs::symlink_metadata(&source_path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                return Err(Error::io(format!("Error while reading {}", source_path.display()), err));
+            }
+        };
+
+        let mode = file_mode(&metadata);
+        let path = system_path.to_string_lossy().to_string();
+        let owner = ownership.get(&path).copied();
+
+        if metadata.file_type().is_symlink() {
+            let link = match std::fs::read_link(&source_path) {
+                Ok(link) => link,
+                Err(err) => {
+                    return Err(Error::io(format!("Error while reading link {}", source_path.display()), err));
+                }
+            };
+
+            transaction.replace(target)?;
+            create_symlink(&link, target)?;
+            set_ownership(owner, target)?;
+
+            manifest.files.push(ManifestEntry {
+                path,
+                kind: EntryKind::Symlink,
+                mode,
+                sha256: None,
+                target: Some(link.to_string_lossy().to_string()),
+            });
+        } else if metadata.is_dir() {
+            let created = !target.is_dir();
+
+            if created {
+                // a file or symlink in the way is set aside, it comes back if the transaction fails
+                if std::fs::symlink_metadata(target).is_ok() {
+                    transaction.replace(target)?;
+                }
+
+                transaction.create_dir(target)?;
+
+                // chown clears the setuid and setgid bits, so the mode is set after it
+                set_ownership(owner, target)?;
+                set_mode(mode, target)?;
+            }
+
+            // directories that were already there belong to the system, or to whoever made them
+            if created || owned_dirs.contains(&path) {
+                manifest.files.push(ManifestEntry {
+                    path: path.clone(),
+                    kind: EntryKind::Directory,
+                    mode,
+                    sha256: None,
+                    target: None,
+                });
+            }
+
+            deploy_dir(&source_path, &system_path, root, ownership, owned_dirs, manifest, transaction)?;
+        } else {
+            transaction.replace(target)?;
+
+            let sha256 = copy_file(&source_path, target)?;
+            set_ownership(owner, target)?;
+            set_mode(mode, target)?;
+
+            manifest.files.push(ManifestEntry {
+                path,
+                kind: EntryKind::File,
+                mode,
+                sha256: Some(sha256),
+                target: None,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes every path in a manifest from the install root
+///
+/// Entries are removed in reverse order so files go before the directories containing them.
+/// Directories are only removed once they are empty, when the transaction is committed, and paths
+/// that another package also owns are left in place.
+///
+/// # Arguments
+/// * `manifest` - The manifest of the package being removed
+/// * `root` - The install root to remove from, or an empty string for the running system
+/// * `shared` - Paths owned by other installed packages
+/// * `transaction` - The transaction every change is recorded in
+pub fn remove(manifest: &Manifest, root: &str, shared: &HashSet<String>, transaction: &mut Transaction) -> Result<()> {
+    for entry in manifest.files.iter().rev() {
+        if shared.contains(&entry.path) {
+            continue;
+        }
+
+        let target = crate::config::resolve_under(root, &entry.path);
+        let target = Path::new(&target);
+
+        // already gone, nothing to do
+        let metadata = match std::fs::symlink_metadata(target) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        match entry.kind {
+            EntryKind::Directory => {
+                // a directory that still has something in it belongs to someone else too
+                if metadata.is_dir() {
+                    transaction.remove_dir_if_empty(target);
+                }
+            }
+            EntryKind::File | EntryKind::Symlink => {
+                if metadata.is_dir() {
+                    return Err(Error::io(format!("Error while removing {}", target.display()), std::io::ErrorKind::IsADirectory.into()));
+                }
+
+                transaction.remove(target)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies a file, hashing it along the way
+///
+/// # Returns
+/// * `Result<String>` - The SHA-256 of the copied file
+fn copy_file(source: &Path, target: &Path) -> Result<String> {
+    let mut input = match File::open(source) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(Error::io(format!("Error while opening {}", source.display()), err));
+        }
+    };
+
+    let output = match File::create(target) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(Error::io(format!("Error while creating {}", target.display()), err));
+        }
+    };
+
+    let mut writer = HashingWriter { inner: output, hasher: Sha256::new() };
+
+    if let Err(err) = std::io::copy(&mut input, &mut writer) {
+        return Err(Error::io(format!("Error while copying {}", source.display()), err));
+    }
+
+    Ok(format!("{:x}", writer.hasher.finalize()))
+}
+
+/// Hashes everything written through it
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o644 }
+}
+
+#[cfg(unix)]
+pub(crate) fn set_mode(mode: u32, target: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match std::fs::set_permissions(target, std::fs::Permissions::from_mode(mode)) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Error::io(format!("Error while setting mode of {}", target.display()), err)),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_mode(_mode: u32, _target: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_ownership(owner: Option<(u32, u32)>, target: &Path) -> Result<()> {
+    let (uid, gid) = match owner {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+
+    match std::os::unix::fs::lchown(target, Some(uid), Some(gid)) {
+        Ok(_) => Ok(()),
+        // unprivileged installs keep the ownership of the user running comet
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => Ok(()),
+        Err(err) => Err(Error::io(format!("Error while setting ownership of {}", target.display()), err)),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_ownership(_owner: Option<(u32, u32)>, _target: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(link: &Path, target: &Path) -> Result<()> {
+    match std::os::unix::fs::symlink(link, target) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(Error::io(format!("Error while creating symlink {}", target.display()), err)),
+    }
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_link: &Path, target: &Path) -> Result<()> {
+    Err(Error::io(format!("Error while creating symlink {}", target.display()), std::io::ErrorKind::Unsupported.into()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// A config for an install root in a temp dir, with an empty installed database
+    fn config(root: &Path) -> Config {
+        let mut config = Config::new(Vec::new(), false, "/var/lib/comet".to_string(), "/tmp".to_string());
+        config.root = root.to_string_lossy().to_string();
+
+        std::fs::create_dir_all(config.storage_path()).unwrap();
+        std::fs::write(format!("{}/repo.yml", config.storage_path()), "").unwrap();
+
+        config
+    }
+
+    /// A payload with a file and a symlink to it in /usr/bin
+    fn payload(dir: &Path) -> std::path::PathBuf {
+        let source = dir.join("package");
+
+        std::fs::create_dir_all(source.join("usr/bin")).unwrap();
+        std::fs::write(source.join("usr/bin/tool"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink("tool", source.join("usr/bin/alias")).unwrap();
+
+        source
+    }
+
+    fn paths(manifest: &Manifest) -> Vec<(&str, EntryKind)> {
+        manifest.files.iter().map(|entry| (entry.path.as_str(), entry.kind)).collect()
+    }
+
+    #[test]
+    fn deploy_keeps_symlinks_as_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let config = config(&root);
+        let source = payload(dir.path());
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let manifest = deploy("tool".to_string(), &source, &config.root, &Ownership::new(), None, &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(paths(&manifest), vec![
+            ("/usr", EntryKind::Directory),
+            ("/usr/bin", EntryKind::Directory),
+            ("/usr/bin/alias", EntryKind::Symlink),
+            ("/usr/bin/tool", EntryKind::File),
+        ]);
+
+        let alias = root.join("usr/bin/alias");
+        assert!(std::fs::symlink_metadata(&alias).unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read_link(&alias).unwrap(), Path::new("tool"));
+        assert_eq!(manifest.files[2].target.as_deref(), Some("tool"));
+    }
+
+    #[test]
+    fn deploy_leaves_existing_directories_out_of_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let config = config(&root);
+        let source = payload(dir.path());
+
+        std::fs::create_dir_all(root.join("usr")).unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let manifest = deploy("tool".to_string(), &source, &config.root, &Ownership::new(), None, &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        assert_eq!(paths(&manifest)[0], ("/usr/bin", EntryKind::Directory));
+    }
+
+    #[test]
+    fn remove_takes_symlinks_and_emptied_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let config = config(&root);
+        let source = payload(dir.path());
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let manifest = deploy("tool".to_string(), &source, &config.root, &Ownership::new(), None, &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        remove(&manifest, &config.root, &HashSet::new(), &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        assert!(std::fs::symlink_metadata(root.join("usr")).is_err());
+    }
+
+    #[test]
+    fn remove_leaves_shared_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let config = config(&root);
+        let source = payload(dir.path());
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let manifest = deploy("tool".to_string(), &source, &config.root, &Ownership::new(), None, &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        // another package also ships the file, and the directories it is in
+        let shared: HashSet<String> = ["/usr", "/usr/bin", "/usr/bin/tool"].iter().map(|path| path.to_string()).collect();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        remove(&manifest, &config.root, &shared, &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        assert!(root.join("usr/bin/tool").is_file());
+        assert!(std::fs::symlink_metadata(root.join("usr/bin/alias")).is_err());
+    }
+
+    #[test]
+    fn removed_symlink_comes_back_on_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let config = config(&root);
+        let source = payload(dir.path());
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let manifest = deploy("tool".to_string(), &source, &config.root, &Ownership::new(), None, &mut transaction).unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        remove(&manifest, &config.root, &HashSet::new(), &mut transaction).unwrap();
+        assert!(transaction.rollback(Error::InvalidArgument("failed".to_string())).is_err());
+
+        assert_eq!(std::fs::read_link(root.join("usr/bin/alias")).unwrap(), Path::new("tool"));
+        assert!(root.join("usr/bin/tool").is_file());
+    }
+}
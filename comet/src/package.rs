@@ -1,45 +1,169 @@
 use std::collections::HashMap;
+use std::fmt;
+use crate::error::{Error, Result};
 
+/// A version following Semantic Versioning 2.0 (https://semver.org), like `1.2.3-rc.1+build.5`
+///
+/// Versions are ordered by precedence: pre-releases come before the release they lead up to, and
+/// their identifiers are compared one by one, numbers numerically and everything else in ASCII
+/// order. Build metadata is kept but ignored when comparing, so `1.0.0+a` equals `1.0.0+b`.
+#[derive(Debug, Clone)]
 pub struct SemVer {
-    pub major: i32,
-    pub minor: i32,
-    pub patch: i32,
-    pub suffix: String
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// The pre-release identifiers after the `-`, empty for a release
+    pub pre: Vec<Identifier>,
+    /// The build metadata identifiers after the `+`
+    pub build: Vec<String>,
+}
+
+/// One dot-separated part of a pre-release
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identifier {
+    Numeric(u64),
+    AlphaNumeric(String),
 }
 
 impl SemVer {
-    pub fn new(major: i32, minor: i32, patch: i32, suffix: String) -> SemVer {
+    /// A release version without pre-release or build metadata
+    pub fn new(major: u64, minor: u64, patch: u64) -> SemVer {
         SemVer {
             major,
             minor,
             patch,
-            suffix
+            pre: Vec::new(),
+            build: Vec::new(),
         }
     }
 
-    pub fn to_string(&self) -> String {
-        format!("{}.{}.{}-{}", self.major, self.minor, self.patch, self.suffix)
+    pub fn from_string(version: String) -> Result<SemVer> {
+        let invalid = |message: &str| Error::Parse {
+            context: format!("Invalid version {}: {}", version, message),
+            source: None,
+        };
+
+        // build metadata is everything after the first "+", the pre-release everything between the first "-" and it
+        let (rest, build) = match version.split_once('+') {
+            Some((rest, build)) => (rest, Some(build)),
+            None => (version.as_str(), None),
+        };
+
+        let (core, pre) = match rest.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (rest, None),
+        };
+
+        let mut numbers = core.split('.');
+        let mut number = |name: &str| -> Result<u64> {
+            let part = numbers.next().ok_or_else(|| invalid(&format!("the {} version is missing", name)))?;
+            parse_number(part).map_err(|message| invalid(&message))
+        };
+
+        let major = number("major")?;
+        let minor = number("minor")?;
+        let patch = number("patch")?;
+
+        if numbers.next().is_some() {
+            return Err(invalid("there are more than three version numbers"));
+        }
+
+        let pre = match pre {
+            Some(pre) => identifiers(pre)
+                .map_err(|message| invalid(&message))?
+                .into_iter()
+                .map(|identifier| match identifier.bytes().all(|byte| byte.is_ascii_digit()) {
+                    true => parse_number(identifier).map(Identifier::Numeric),
+                    false => Ok(Identifier::AlphaNumeric(identifier.to_string())),
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|message| invalid(&message))?,
+            None => Vec::new(),
+        };
+
+        let build = match build {
+            Some(build) => identifiers(build)
+                .map_err(|message| invalid(&message))?
+                .into_iter()
+                .map(|identifier| identifier.to_string())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(SemVer { major, minor, patch, pre, build })
     }
 
-    pub fn from_string(version: String) -> SemVer {
-        let mut split = version.split(".");
-        let major = split.next().unwrap().parse::<i32>().unwrap();
-        let minor = split.next().unwrap().parse::<i32>().unwrap();
-        let mut split = split.next().unwrap().split("-");
-        let patch = split.next().unwrap().parse::<i32>().unwrap();
-        let suffix = split.next().unwrap().to_string();
-        SemVer {
-            major,
-            minor,
-            patch,
-            suffix
+    /// Whether this is a pre-release, like `1.0.0-rc.1`
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+}
+
+/// Parses a version number or numeric pre-release identifier, which can't have leading zeros
+fn parse_number(number: &str) -> std::result::Result<u64, String> {
+    if number.is_empty() || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(format!("\"{}\" is not a number", number));
+    }
+
+    if number.len() > 1 && number.starts_with('0') {
+        return Err(format!("{} has a leading zero", number));
+    }
+
+    number.parse().map_err(|_| format!("{} is too large", number))
+}
+
+/// Splits dot-separated pre-release or build identifiers, which have to be non-empty and made of `[0-9A-Za-z-]`
+fn identifiers(identifiers: &str) -> std::result::Result<Vec<&str>, String> {
+    identifiers.split('.').map(|identifier| {
+        if identifier.is_empty() {
+            return Err("an identifier is empty".to_string());
+        }
+
+        if !identifier.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-') {
+            return Err(format!("{} may only contain letters, digits and hyphens", identifier));
+        }
+
+        Ok(identifier)
+    }).collect()
+}
+
+impl std::str::FromStr for SemVer {
+    type Err = Error;
+
+    fn from_str(version: &str) -> Result<SemVer> {
+        SemVer::from_string(version.to_string())
+    }
+}
+
+impl fmt::Display for SemVer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+
+        if !self.pre.is_empty() {
+            let pre: Vec<String> = self.pre.iter().map(|identifier| identifier.to_string()).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build.join("."))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier::Numeric(number) => write!(f, "{}", number),
+            Identifier::AlphaNumeric(identifier) => write!(f, "{}", identifier),
         }
     }
 }
 
 impl PartialEq for SemVer {
     fn eq(&self, other: &Self) -> bool {
-        self.major == other.major && self.minor == other.minor && self.patch == other.patch && self.suffix == other.suffix
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 
@@ -47,43 +171,175 @@ impl Eq for SemVer {}
 
 impl PartialOrd for SemVer {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        if self.major > other.major {
-            Some(std::cmp::Ordering::Greater)
-        } else if self.major < other.major {
-            Some(std::cmp::Ordering::Less)
-        } else if self.minor > other.minor {
-            Some(std::cmp::Ordering::Greater)
-        } else if self.minor < other.minor {
-            Some(std::cmp::Ordering::Less)
-        } else if self.patch > other.patch {
-            Some(std::cmp::Ordering::Greater)
-        } else if self.patch < other.patch {
-            Some(std::cmp::Ordering::Less)
-        }
-        else if self.suffix != "" && other.suffix == "" {
-            Some(std::cmp::Ordering::Less)
-        }
-        else if self.suffix == "" && other.suffix != "" {
-            Some(std::cmp::Ordering::Greater)
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SemVer {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.major.cmp(&other.major)
+            .then(self.minor.cmp(&other.minor))
+            .then(self.patch.cmp(&other.patch))
+            .then_with(|| {
+                // a pre-release comes before the release, otherwise the identifiers decide and a longer list wins a tie
+                match (self.pre.is_empty(), other.pre.is_empty()) {
+                    (false, true) => std::cmp::Ordering::Less,
+                    (true, false) => std::cmp::Ordering::Greater,
+                    _ => self.pre.cmp(&other.pre),
+                }
+            })
+    }
+}
+
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Identifier {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (self, other) {
+            (Identifier::Numeric(a), Identifier::Numeric(b)) => a.cmp(b),
+            // numbers always come before anything with letters in it
+            (Identifier::Numeric(_), Identifier::AlphaNumeric(_)) => std::cmp::Ordering::Less,
+            (Identifier::AlphaNumeric(_), Identifier::Numeric(_)) => std::cmp::Ordering::Greater,
+            (Identifier::AlphaNumeric(a), Identifier::AlphaNumeric(b)) => a.cmp(b),
         }
-        else if self.suffix != "" && other.suffix != "" {
-            Some(self.suffix.cmp(&other.suffix))
+    }
+}
+
+impl serde::Serialize for SemVer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SemVer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<SemVer, D::Error> {
+        let version = String::deserialize(deserializer)?;
+
+        SemVer::from_string(version).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The full version of a package, shown as `epoch:version-release`
+///
+/// The epoch is compared first, so bumping it makes a package newer than every version with a
+/// lower epoch, which allows going back to an older upstream version. The release counts rebuilds
+/// of the same upstream version and is compared last. Both are left out when they are 0.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PackageVersion {
+    pub epoch: u64,
+    pub version: SemVer,
+    pub release: u64,
+}
+
+impl fmt::Display for PackageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_version(f, self.epoch, &self.version.to_string(), self.release)
+    }
+}
+
+/// Writes a version as `epoch:version-release`, leaving out an epoch or release of 0
+fn write_version(f: &mut impl fmt::Write, epoch: u64, version: &str, release: u64) -> fmt::Result {
+    if epoch > 0 {
+        write!(f, "{}:", epoch)?;
+    }
+
+    write!(f, "{}", version)?;
+
+    if release > 0 {
+        write!(f, "-{}", release)?;
+    }
+
+    Ok(())
+}
+
+fn is_zero(number: &u64) -> bool {
+    *number == 0
+}
+
+/// Why an installed package is there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    /// It was asked for, packages installed before reasons were recorded count as this too
+    #[default]
+    Explicit,
+    /// It was only pulled in for another package, so `autoremove` removes it once nothing needs it
+    Dependency,
+}
+
+impl InstallReason {
+    pub fn is_explicit(&self) -> bool {
+        *self == InstallReason::Explicit
+    }
+}
+
+impl fmt::Display for InstallReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstallReason::Explicit => write!(f, "explicit"),
+            InstallReason::Dependency => write!(f, "dependency"),
         }
-        else {
-            Some(std::cmp::Ordering::Equal)
+    }
+}
+
+impl std::str::FromStr for InstallReason {
+    type Err = Error;
+
+    fn from_str(reason: &str) -> Result<InstallReason> {
+        match reason {
+            "explicit" => Ok(InstallReason::Explicit),
+            "dependency" => Ok(InstallReason::Dependency),
+            other => Err(Error::InvalidArgument(format!("{} is not an install reason, use explicit or dependency", other))),
         }
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Package {
     pub name: String,
     pub description: String,
+    /// The upstream version, see `SemVer`
     pub version: String,
+    /// Bumped to go back to an older upstream version, 0 if not set (see `PackageVersion`)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub epoch: u64,
+    /// Bumped for every rebuild of the same upstream version, 0 if not set
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub release: u64,
     pub dependencies: HashMap<String, String>,
+    /// Packages that are installed along with this one unless asked not to, as a map of name to
+    /// version constraint; installing goes ahead without them if they can't be installed
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub recommends: HashMap<String, String>,
+    /// Packages that go well with this one, which are only mentioned when it is installed
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub suggests: HashMap<String, String>,
+    /// Packages that can't be installed alongside this one, as a map of name to version constraint
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub conflicts: HashMap<String, String>,
+    /// Virtual packages this one stands in for, with the version it provides them at if it has one
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub provides: HashMap<String, Option<String>>,
+    /// Packages this one takes over from, as a map of name to version constraint; they are removed
+    /// when this one is installed
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub replaces: HashMap<String, String>,
     pub authors: Vec<String>,
     pub license: String,
-    pub checksum: Option<String>
+    pub checksum: Option<String>,
+    /// The galaxy a cached package was fetched from, only set in cache.yml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub galaxy: Option<String>,
+    /// Where the star is in its galaxy, relative to the galaxy URL; `<name>.star` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub star: Option<String>,
+    /// Why the package is installed, only set in repo.yml
+    #[serde(default, skip_serializing_if = "InstallReason::is_explicit")]
+    pub reason: InstallReason,
 }
 
 impl Package {
@@ -92,30 +348,62 @@ impl Package {
             name,
             description,
             version,
+            epoch: 0,
+            release: 0,
             dependencies: stars,
+            recommends: HashMap::new(),
+            suggests: HashMap::new(),
+            conflicts: HashMap::new(),
+            provides: HashMap::new(),
+            replaces: HashMap::new(),
             authors,
             license,
-            checksum: None
+            checksum: None,
+            galaxy: None,
+            star: None,
+            reason: InstallReason::Explicit,
         }
     }
 
-    pub fn to_string(&self) -> String {
-        serde_yaml::to_string(&self).unwrap()
+    /// The epoch, upstream version and release of the package together
+    pub fn full_version(&self) -> Result<PackageVersion> {
+        Ok(PackageVersion {
+            epoch: self.epoch,
+            version: SemVer::from_string(self.version.clone())?,
+            release: self.release,
+        })
+    }
+
+    /// The version as `epoch:version-release`, which works even if the upstream version is invalid
+    pub fn display_version(&self) -> String {
+        let mut version = String::new();
+        let _ = write_version(&mut version, self.epoch, &self.version, self.release);
+
+        version
+    }
+
+    pub fn from_string(package: String) -> Result<Package> {
+        serde_yaml::from_str(&package).map_err(|err| Error::parse("Error while parsing package info", err))
     }
 
-    pub fn from_string(package: String) -> Package {
-        serde_yaml::from_str(&package).unwrap()
+    pub fn from_file(path: String) -> Result<Package> {
+        let package = std::fs::read_to_string(path.clone())
+            .map_err(|err| Error::io(format!("Error while reading package file {}", path), err))?;
+
+        serde_yaml::from_str(&package).map_err(|err| Error::parse(format!("Error while parsing package file {}", path), err))
     }
+}
 
-    pub fn from_file(path: String) -> Package {
-        let package = std::fs::read_to_string(path).unwrap();
-        serde_yaml::from_str(&package).unwrap()
+impl fmt::Display for Package {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let package = serde_yaml::to_string(&self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", package)
     }
 }
 
 impl PartialEq for Package {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name && self.version == other.version
+        self.name == other.name && self.epoch == other.epoch && self.version == other.version && self.release == other.release
     }
 }
 
@@ -123,12 +411,14 @@ impl Eq for Package {}
 
 impl PartialOrd for Package {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        let self_version = SemVer::from_string(self.version.clone());
-        let other_version = SemVer::from_string(other.version.clone());
-        if self.name == other.name {
-            Some(self_version.partial_cmp(&other_version).unwrap())
-        } else {
-            None
+        if self.name != other.name {
+            return None;
         }
+
+        // packages with unparseable versions can't be ordered
+        let self_version = self.full_version().ok()?;
+        let other_version = other.full_version().ok()?;
+
+        self_version.partial_cmp(&other_version)
     }
 }
\ No newline at end of file
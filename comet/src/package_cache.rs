@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+use crate::package::Package;
+use crate::repo::Repository;
+
+/// How many stars a package cache holds, or how many were removed from it, and their size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheUsage {
+    pub stars: usize,
+    pub bytes: u64,
+}
+
+/// The name a star is kept under in the package cache, `<name>-<version>-<checksum>.star`
+///
+/// Only stars with a known checksum are cached, so a cached star can always be checked before it
+/// is reused.
+///
+/// # Arguments
+/// * `package` - The cache entry of the package
+pub fn file_name(package: &Package) -> Option<String> {
+    let checksum = package.checksum.as_deref()?;
+
+    Some(format!("{}-{}-{}.star", package.name, package.version, checksum.to_ascii_lowercase()))
+}
+
+/// Adds up the stars in a package cache, including partial downloads
+///
+/// # Arguments
+/// * `dir` - The package cache directory
+pub fn usage(dir: &str) -> Result<CacheUsage> {
+    let mut usage = CacheUsage::default();
+
+    for (_, size) in entries(dir)? {
+        usage.stars += 1;
+        usage.bytes += size;
+    }
+
+    Ok(usage)
+}
+
+/// Removes every star from a package cache
+///
+/// # Arguments
+/// * `dir` - The package cache directory
+///
+/// # Returns
+/// * `Result<CacheUsage>` - What was removed
+pub fn clean(dir: &str) -> Result<CacheUsage> {
+    remove_where(dir, |_| true)
+}
+
+/// Removes the stars that no galaxy offers anymore
+///
+/// A star is kept if the cache lists the same name, version and checksum, so it could still be
+/// downloaded. Partial downloads of such stars are kept too, so they can still be resumed.
+///
+/// # Arguments
+/// * `dir` - The package cache directory
+/// * `available` - The packages in the cache
+///
+/// # Returns
+/// * `Result<CacheUsage>` - What was removed
+pub fn autoclean(dir: &str, available: &Repository) -> Result<CacheUsage> {
+    let wanted: HashSet<String> = available.packages.values().filter_map(file_name).collect();
+
+    remove_where(dir, |file_name| {
+        let star = file_name.strip_suffix(".part").unwrap_or(file_name);
+        !wanted.contains(star)
+    })
+}
+
+/// Removes the stars whose file name matches
+fn remove_where(dir: &str, remove: impl Fn(&str) -> bool) -> Result<CacheUsage> {
+    let mut removed = CacheUsage::default();
+
+    for (path, size) in entries(dir)? {
+        let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+
+        if !remove(&file_name) {
+            continue;
+        }
+
+        std::fs::remove_file(&path)
+            .map_err(|err| Error::io(format!("Error while removing {}", path.display()), err))?;
+
+        // a partial download keeps what it is a part of next to it
+        if file_name.ends_with(".part") {
+            let _ = std::fs::remove_file(crate::transport::validator_path(&path.to_string_lossy()));
+        }
+
+        removed.stars += 1;
+        removed.bytes += size;
+    }
+
+    Ok(removed)
+}
+
+/// Lists the stars and partial downloads in a package cache with their sizes, a missing directory is empty
+fn entries(dir: &str) -> Result<Vec<(PathBuf, u64)>> {
+    if !Path::new(dir).exists() {
+        return Ok(Vec::new());
+    }
+
+    let read_error = |err| Error::io(format!("Error while reading package cache {}", dir), err);
+    let mut entries = Vec::new();
+
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if !name.ends_with(".star") && !name.ends_with(".star.part") {
+            continue;
+        }
+
+        let metadata = entry.metadata().map_err(read_error)?;
+
+        if metadata.is_file() {
+            entries.push((path, metadata.len()));
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str, checksum: &str) -> Package {
+        let mut package = Package::new(name.to_string(), String::new(), version.to_string(), Default::default(), "MIT".to_string(), Vec::new());
+        package.checksum = Some(checksum.to_string());
+        package
+    }
+
+    /// A package cache with two versions of a star, a partial download, and a file that isn't a star
+    fn cache() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+
+        std::fs::write(dir.path().join("hello-1.0.0-aa.star"), [0; 10]).unwrap();
+        std::fs::write(dir.path().join("hello-2.0.0-bb.star"), [0; 20]).unwrap();
+        std::fs::write(dir.path().join("hello-3.0.0-cc.star.part"), [0; 5]).unwrap();
+        std::fs::write(dir.path().join("hello-3.0.0-cc.star.part.validator"), "\"v3\"").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), [0; 100]).unwrap();
+        std::fs::create_dir(dir.path().join("old.star")).unwrap();
+
+        (dir, path)
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn file_name_needs_a_checksum() {
+        assert_eq!(file_name(&package("hello", "1.0.0", "AA")).as_deref(), Some("hello-1.0.0-aa.star"));
+
+        let mut unchecked = package("hello", "1.0.0", "aa");
+        unchecked.checksum = None;
+        assert_eq!(file_name(&unchecked), None);
+    }
+
+    #[test]
+    fn usage_counts_stars_and_partial_downloads() {
+        let (_dir, path) = cache();
+
+        assert_eq!(usage(&path).unwrap(), CacheUsage { stars: 3, bytes: 35 });
+        assert_eq!(usage(&format!("{}/missing", path)).unwrap(), CacheUsage::default());
+    }
+
+    #[test]
+    fn clean_removes_every_star() {
+        let (dir, path) = cache();
+
+        assert_eq!(clean(&path).unwrap(), CacheUsage { stars: 3, bytes: 35 });
+        assert_eq!(names(dir.path()), vec!["notes.txt", "old.star"]);
+        assert_eq!(usage(&path).unwrap(), CacheUsage::default());
+    }
+
+    #[test]
+    fn autoclean_keeps_what_galaxies_offer() {
+        let (dir, path) = cache();
+
+        let mut available = Repository::new(true);
+        available.add_package(package("hello", "3.0.0", "cc"));
+        available.add_package(package("other", "1.0.0", "aa"));
+
+        // a star of an older version is gone, but the download of the current one can still resume
+        assert_eq!(autoclean(&path, &available).unwrap(), CacheUsage { stars: 2, bytes: 30 });
+        assert_eq!(names(dir.path()), vec!["hello-3.0.0-cc.star.part", "hello-3.0.0-cc.star.part.validator", "notes.txt", "old.star"]);
+    }
+}
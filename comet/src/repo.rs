@@ -1,10 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::{Write};
 use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::galaxy::GalaxyIndex;
+use crate::signature::TrustedKeys;
 use crate::package::Package;
 
-#[derive(Serialize, Deserialize)]
+/// The outcome of refreshing the cache from every configured galaxy
+#[derive(Debug)]
+pub struct CacheUpdate {
+    /// The galaxies whose repo.yml changed, with the mirror each one was fetched from
+    pub updated: Vec<(String, String)>,
+    /// The galaxies whose repo.yml is the same as last time, with the mirror that said so
+    pub unchanged: Vec<(String, String)>,
+    /// The galaxies that couldn't be fetched, whose previously cached packages were kept
+    pub failed: Vec<(String, Error)>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Repository {
     pub packages: HashMap<String, Package>
 }
@@ -12,7 +27,7 @@ pub struct Repository {
 impl Repository {
     pub fn new(empty: bool) -> Repository {
         if empty {
-            return Repository {
+            Repository {
                 packages: HashMap::new()
             }
         } else {
@@ -62,39 +77,249 @@ impl Repository {
         self.packages.get(&package)
     }
 
-    pub fn to_string(&self) -> String {
-        serde_yaml::to_string(&self).unwrap()
+    /// Writes the repository to a file atomically
+    ///
+    /// The contents go to a temporary file next to the target first, which is then renamed over
+    /// it, so a crash never leaves a half-written database behind
+    pub fn to_file(&self, path: String) -> Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+
+        let mut file = File::create(&tmp_path)
+            .map_err(|err| Error::io(format!("Error while creating {}", tmp_path), err))?;
+        file.write_all(self.to_string().as_bytes())
+            .map_err(|err| Error::io(format!("Error while writing {}", tmp_path), err))?;
+        file.sync_all()
+            .map_err(|err| Error::io(format!("Error while writing {}", tmp_path), err))?;
+
+        std::fs::rename(&tmp_path, &path)
+            .map_err(|err| Error::io(format!("Error while replacing {}", path), err))
     }
 
-    pub fn to_file(&self, path: String) {
-        let mut file = File::create(path).unwrap();
-        file.write_all(self.to_string().as_bytes()).unwrap();
+    pub fn from_string(repository: String) -> Result<Repository> {
+        serde_yaml::from_str(&repository).map_err(|err| Error::parse("Error while parsing repository", err))
     }
 
-    pub fn from_string(repository: String) -> Repository {
-        serde_yaml::from_str(&repository).unwrap()
+    pub fn from_file(path: String) -> Result<Repository> {
+        let repository = std::fs::read_to_string(path.clone())
+            .map_err(|err| Error::io(format!("Error while reading {}", path), err))?;
+
+        // an empty file is an empty repository
+        if repository.trim().is_empty() {
+            return Ok(Repository::new(true));
+        }
+
+        serde_yaml::from_str(&repository).map_err(|err| Error::parse(format!("Error while parsing {}", path), err))
     }
 
-    pub fn from_file(path: String) -> Repository {
-        let repository = std::fs::read_to_string(path).unwrap();
-        serde_yaml::from_str(&repository).unwrap_or_else(|_| Repository::new(true))
+    pub fn from_web(url: String) -> Result<Repository> {
+        Repository::from_web_signed(url, &TrustedKeys::new())
     }
 
-    pub fn from_web(url: String) -> Repository {
-        let repo_file = format!("{}/repo.yml", url.clone());
-        let repository = reqwest::blocking::get(&repo_file).unwrap().text().unwrap();
-        serde_yaml::from_str(&repository).unwrap()
+    /// Downloads a galaxy's repo.yml, checking its signature against the trusted keys
+    ///
+    /// The signature is read from `repo.yml.sig` next to it. If no keys are trusted, nothing is
+    /// checked; otherwise a missing or bad signature is an error.
+    ///
+    /// # Arguments
+    /// * `url` - The URL of the galaxy, or a `file://` URL or absolute path for one on disk
+    /// * `keys` - The keys the galaxy must be signed with
+    pub fn from_web_signed(url: String, keys: &TrustedKeys) -> Result<Repository> {
+        match GalaxyIndex::fetch(&url, keys, None)? {
+            Some(index) => Ok(index.repository),
+            // nothing to compare against, so the server has no reason to say it is unchanged
+            None => Err(Error::Parse {
+                context: format!("{} answered 304 Not Modified to an unconditional request", url),
+                source: None,
+            }),
+        }
     }
 
+    /// Whether another installed package depends on this one
+    ///
+    /// Depending on a virtual package the package provides counts too, unless another installed
+    /// package provides it as well.
     pub fn is_dependency(&self, package: String) -> bool {
+        let mut names = vec![package.clone()];
+
+        if let Some(installed) = self.packages.get(&package) {
+            names.extend(installed.provides.keys()
+                .filter(|name| !self.packages.values().any(|other| other.name != package && other.provides.contains_key(*name)))
+                .cloned());
+        }
+
         for (_, p) in self.packages.iter() {
+            if p.name == package {
+                continue;
+            }
+
             // key is the name of the dependency
             for (key, _) in p.dependencies.iter() {
-                if key == &package {
+                if names.contains(key) {
                     return true;
                 }
             }
         }
         false
     }
-}
\ No newline at end of file
+
+    /// The packages installed as dependencies that no explicitly installed package needs anymore
+    ///
+    /// Explicit packages need what they depend on and recommend, and so do the packages they need
+    /// in turn. A dependency on a virtual package is met by every installed package providing it.
+    ///
+    /// # Returns
+    /// * `Vec<String>` - The names of the orphaned packages, each before the orphans it depends on
+    pub fn orphans(&self) -> Vec<String> {
+        let mut needed: HashSet<&str> = HashSet::new();
+        let mut queue: Vec<&Package> = self.packages.values().filter(|package| package.reason.is_explicit()).collect();
+
+        while let Some(package) = queue.pop() {
+            if !needed.insert(&package.name) {
+                continue;
+            }
+
+            for name in package.dependencies.keys().chain(package.recommends.keys()) {
+                queue.extend(self.packages.values()
+                    .filter(|other| &other.name == name || other.provides.contains_key(name))
+                    .filter(|other| !needed.contains(other.name.as_str())));
+            }
+        }
+
+        let mut orphans: Vec<&str> = self.packages.keys().map(|name| name.as_str()).filter(|name| !needed.contains(name)).collect();
+        orphans.sort();
+
+        // dependencies end up before the packages needing them, so the list is reversed after
+        let mut ordered = Vec::new();
+        let mut visited = HashSet::new();
+
+        for name in &orphans {
+            self.visit_orphan(name, &orphans, &mut visited, &mut ordered);
+        }
+
+        ordered.reverse();
+        ordered
+    }
+
+    /// Adds an orphan to `ordered` after the orphans it depends on, for `orphans`
+    fn visit_orphan(&self, name: &str, orphans: &[&str], visited: &mut HashSet<String>, ordered: &mut Vec<String>) {
+        // an orphan that is still being visited is part of a cycle, it is added once its visit ends
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+
+        if let Some(package) = self.packages.get(name) {
+            let mut dependencies: Vec<&String> = package.dependencies.keys().collect();
+            dependencies.sort();
+
+            for dependency in dependencies {
+                // a virtual dependency is met by every orphan providing it
+                for other in orphans {
+                    let provides = self.packages.get(*other).map(|other| other.provides.contains_key(dependency)).unwrap_or(false);
+
+                    if *other == dependency || provides {
+                        self.visit_orphan(other, orphans, visited, ordered);
+                    }
+                }
+            }
+        }
+
+        ordered.push(name.to_string());
+    }
+}
+
+impl fmt::Display for Repository {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let repository = serde_yaml::to_string(&self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", repository)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::InstallReason;
+
+    /// An installed package depending on the given packages
+    fn package(name: &str, dependencies: &[&str], reason: InstallReason) -> Package {
+        let dependencies = dependencies.iter().map(|name| (name.to_string(), "1.0.0".to_string())).collect();
+        let mut package = Package::new(name.to_string(), String::new(), "1.0.0".to_string(), dependencies, "MIT".to_string(), Vec::new());
+        package.reason = reason;
+        package
+    }
+
+    fn repo(packages: Vec<Package>) -> Repository {
+        let mut repo = Repository::new(true);
+
+        for package in packages {
+            repo.add_package(package);
+        }
+
+        repo
+    }
+
+    #[test]
+    fn chains_are_removed_from_the_top() {
+        let repo = repo(vec![
+            package("c", &[], InstallReason::Dependency),
+            package("a", &["b"], InstallReason::Dependency),
+            package("b", &["c"], InstallReason::Dependency),
+        ]);
+
+        assert_eq!(repo.orphans(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn diamonds_are_removed_before_what_they_share() {
+        let repo = repo(vec![
+            package("top", &["left", "right"], InstallReason::Dependency),
+            package("left", &["bottom"], InstallReason::Dependency),
+            package("right", &["bottom"], InstallReason::Dependency),
+            package("bottom", &[], InstallReason::Dependency),
+        ]);
+
+        assert_eq!(repo.orphans(), vec!["top", "right", "left", "bottom"]);
+    }
+
+    #[test]
+    fn explicit_packages_keep_what_they_need() {
+        let mut lib = package("lib", &["base"], InstallReason::Dependency);
+        lib.recommends.insert("extra".to_string(), "1.0.0".to_string());
+
+        let repo = repo(vec![
+            package("app", &["lib"], InstallReason::Explicit),
+            lib,
+            package("base", &[], InstallReason::Dependency),
+            package("extra", &[], InstallReason::Dependency),
+            package("leftover", &["base"], InstallReason::Dependency),
+            package("tool", &[], InstallReason::Explicit),
+        ]);
+
+        assert_eq!(repo.orphans(), vec!["leftover"]);
+    }
+
+    #[test]
+    fn virtual_dependencies_are_met_by_their_providers() {
+        let mut vim = package("vim", &[], InstallReason::Dependency);
+        vim.provides.insert("editor".to_string(), None);
+
+        let mut nano = package("nano", &[], InstallReason::Dependency);
+        nano.provides.insert("editor".to_string(), None);
+
+        let orphans = repo(vec![package("git", &["editor"], InstallReason::Dependency), vim.clone(), nano]).orphans();
+        assert_eq!(orphans, vec!["git", "vim", "nano"]);
+
+        // an explicit package needing the virtual package keeps every provider
+        let orphans = repo(vec![package("git", &["editor"], InstallReason::Explicit), vim]).orphans();
+        assert!(orphans.is_empty());
+    }
+
+    #[test]
+    fn cycles_are_listed_once() {
+        let repo = repo(vec![
+            package("a", &["b"], InstallReason::Dependency),
+            package("b", &["a"], InstallReason::Dependency),
+        ]);
+
+        assert_eq!(repo.orphans(), vec!["a", "b"]);
+    }
+}
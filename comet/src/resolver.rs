@@ -0,0 +1,1048 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use crate::error::{Error, Result};
+use crate::package::{Package, SemVer};
+use crate::repo::Repository;
+
+/// A comparison against a single version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+#[derive(Debug, Clone)]
+struct Comparator {
+    op: Op,
+    version: SemVer,
+}
+
+impl Comparator {
+    fn matches(&self, version: &SemVer) -> bool {
+        match self.op {
+            Op::Eq => version == &self.version,
+            Op::Gt => version > &self.version,
+            Op::Ge => version >= &self.version,
+            Op::Lt => version < &self.version,
+            Op::Le => version <= &self.version,
+        }
+    }
+}
+
+/// A version constraint such as `>=1.2, <2`, `^1.4`, `~1.2.3` or `=1.0.0`
+///
+/// Every comma-separated part has to match. A bare version like `1.0.0` means "at least this
+/// version", which is how dependencies were written before constraints existed. An empty
+/// constraint or `*` matches any version.
+///
+/// Like Cargo and npm, other constraints only match a pre-release such as `2.0.0-rc.1` if one of
+/// their parts names a pre-release of the same version, like `>=2.0.0-beta`. So `<2` doesn't
+/// match `2.0.0-rc.1`, and `^1.4` doesn't match `1.5.0-alpha`.
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    text: String,
+    comparators: Vec<Comparator>,
+}
+
+impl Constraint {
+    /// A constraint matching any version
+    pub fn any() -> Constraint {
+        Constraint {
+            text: "*".to_string(),
+            comparators: Vec::new(),
+        }
+    }
+
+    pub fn from_string(constraint: String) -> Result<Constraint> {
+        let mut comparators = Vec::new();
+
+        for part in constraint.split(',') {
+            let part = part.trim();
+
+            if part.is_empty() || part == "*" {
+                continue;
+            }
+
+            comparators.extend(parse_part(part).map_err(|message| Error::Parse {
+                context: format!("Invalid version constraint {}: {}", constraint, message),
+                source: None,
+            })?);
+        }
+
+        let text = if comparators.is_empty() { "*".to_string() } else { constraint.trim().to_string() };
+
+        Ok(Constraint { text, comparators })
+    }
+
+    /// Whether the constraint matches any version
+    pub fn is_any(&self) -> bool {
+        self.comparators.is_empty()
+    }
+
+    /// Checks if a version satisfies every part of the constraint
+    pub fn matches(&self, version: &SemVer) -> bool {
+        if !self.comparators.iter().all(|comparator| comparator.matches(version)) {
+            return false;
+        }
+
+        // a pre-release has to be asked for, by naming a pre-release of the same version
+        !version.is_prerelease() || self.is_any() || self.comparators.iter().any(|comparator| {
+            comparator.version.is_prerelease()
+                && (comparator.version.major, comparator.version.minor, comparator.version.patch) == (version.major, version.minor, version.patch)
+        })
+    }
+
+    /// Checks if a version string satisfies the constraint, treating unparseable versions as not matching
+    pub fn matches_str(&self, version: &str) -> bool {
+        match SemVer::from_string(version.to_string()) {
+            Ok(version) => self.matches(&version),
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Parses one comma-separated part of a constraint into the comparators it stands for
+fn parse_part(part: &str) -> std::result::Result<Vec<Comparator>, String> {
+    let (operator, version) = match part.find(|c: char| c.is_ascii_digit()) {
+        Some(index) => part.split_at(index),
+        None => return Err(format!("no version in {}", part)),
+    };
+
+    let (major, minor, patch, lower) = parse_partial(version)?;
+
+    let comparator = |op, version| Comparator { op, version };
+
+    // an upper bound past the largest version there can be is no bound at all
+    let range = |lower, upper: Option<SemVer>| {
+        std::iter::once(comparator(Op::Ge, lower)).chain(upper.map(|upper| comparator(Op::Lt, upper))).collect()
+    };
+
+    let comparators = match operator.trim() {
+        // a bare version is a minimum version
+        "" | ">=" => vec![comparator(Op::Ge, lower)],
+        ">" => vec![comparator(Op::Gt, lower)],
+        "<" => vec![comparator(Op::Lt, lower)],
+        "<=" => vec![comparator(Op::Le, lower)],
+        "=" | "==" => match (minor, patch) {
+            (Some(_), Some(_)) => vec![comparator(Op::Eq, lower)],
+            // "=1.2" is any 1.2.x, "=1" is any 1.x.y
+            _ => range(lower, bump(major, minor, None)),
+        },
+        "^" => {
+            // the first non-zero part can't change
+            let upper = if major > 0 || minor.is_none() {
+                bump(major, None, None)
+            } else if minor.unwrap_or(0) > 0 || patch.is_none() {
+                bump(major, minor, None)
+            } else {
+                bump(major, minor, patch)
+            };
+
+            range(lower, upper)
+        }
+        "~" => {
+            // patch changes are allowed, or minor changes if only the major version was given
+            let upper = match minor {
+                Some(_) => bump(major, minor, None),
+                None => bump(major, None, None),
+            };
+
+            range(lower, upper)
+        }
+        other => return Err(format!("unknown operator {}", other)),
+    };
+
+    Ok(comparators)
+}
+
+/// Parses a version that may leave out the minor and patch numbers, like `1` or `1.2-rc.1`
+///
+/// # Returns
+/// * The numbers that were given, and the full version with the missing numbers set to 0
+fn parse_partial(version: &str) -> std::result::Result<(u64, Option<u64>, Option<u64>, SemVer), String> {
+    let version = version.trim();
+    let (numbers, rest) = version.split_at(version.find(['-', '+']).unwrap_or(version.len()));
+
+    let mut parts = Vec::new();
+
+    for number in numbers.split('.') {
+        match number.parse::<u64>() {
+            Ok(number) => parts.push(number),
+            Err(_) => return Err(format!("invalid version {}", version)),
+        }
+    }
+
+    let (major, minor, patch) = match parts.as_slice() {
+        [major] => (*major, None, None),
+        [major, minor] => (*major, Some(*minor), None),
+        [major, minor, patch] => (*major, Some(*minor), Some(*patch)),
+        _ => return Err(format!("invalid version {}", version)),
+    };
+
+    // the pre-release and build metadata are checked by the full version parser
+    let full = format!("{}.{}.{}{}", major, minor.unwrap_or(0), patch.unwrap_or(0), rest);
+    let lower = SemVer::from_string(full).map_err(|err| err.to_string())?;
+
+    Ok((major, minor, patch, lower))
+}
+
+/// The smallest version above everything that starts with the given numbers
+///
+/// A number that is already as large as it gets carries over into the one before it, like 1.2.max
+/// becomes 1.3.0, and there is no such version if the major version can't go up either.
+fn bump(major: u64, minor: Option<u64>, patch: Option<u64>) -> Option<SemVer> {
+    match (minor, patch) {
+        (Some(minor), Some(patch)) => match patch.checked_add(1) {
+            Some(patch) => Some(SemVer::new(major, minor, patch)),
+            None => bump(major, Some(minor), None),
+        },
+        (Some(minor), None) => match minor.checked_add(1) {
+            Some(minor) => Some(SemVer::new(major, minor, 0)),
+            None => bump(major, None, None),
+        },
+        _ => major.checked_add(1).map(|major| SemVer::new(major, 0, 0)),
+    }
+}
+
+/// A package the caller asked for
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub name: String,
+    pub constraint: Constraint,
+    /// Only consider the available version, even if an installed one would do (for reinstalls
+    /// and updates)
+    pub from_available: bool,
+    /// Also install what new packages recommend, as far as it can be installed
+    pub recommends: bool,
+}
+
+impl Request {
+    pub fn new(name: String) -> Request {
+        Request {
+            name,
+            constraint: Constraint::any(),
+            from_available: false,
+            recommends: true,
+        }
+    }
+}
+
+/// What happens to a package in a plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Install,
+    Reinstall,
+    Upgrade { from: String },
+    Downgrade { from: String },
+}
+
+/// Whether a package is, or provides, the given name at a version matching the constraint
+///
+/// A package provided without a version only satisfies constraints that match any version.
+///
+/// # Arguments
+/// * `package` - The package to check
+/// * `name` - The name of a real or virtual package
+/// * `constraint` - The versions that are acceptable
+pub fn satisfies(package: &Package, name: &str, constraint: &Constraint) -> bool {
+    if package.name == name {
+        return constraint.matches_str(&package.version);
+    }
+
+    match package.provides.get(name) {
+        Some(Some(version)) => constraint.matches_str(version),
+        Some(None) => constraint.is_any(),
+        None => false,
+    }
+}
+
+/// Finds an available package that takes over from an installed one through `replaces`
+///
+/// # Arguments
+/// * `installed` - The installed packages (`repo.yml`)
+/// * `available` - The packages that can be installed (`cache.yml`)
+/// * `package` - The installed package that might be replaced
+pub fn replacement<'a>(installed: &Repository, available: &'a Repository, package: &Package) -> Option<&'a Package> {
+    let mut replacements: Vec<&Package> = available.packages.values()
+        .filter(|candidate| candidate.name != package.name && installed.get_package(candidate.name.clone()).is_none())
+        .filter(|candidate| replaces(candidate, package))
+        .collect();
+
+    replacements.sort_by(|a, b| a.name.cmp(&b.name));
+    replacements.into_iter().next()
+}
+
+/// Whether a package's `replaces` covers another package, invalid constraints never match
+fn replaces(package: &Package, other: &Package) -> bool {
+    package.replaces.get(&other.name)
+        .and_then(|constraint| Constraint::from_string(constraint.clone()).ok())
+        .map(|constraint| constraint.matches_str(&other.version))
+        .unwrap_or(false)
+}
+
+/// A single package to install as part of a plan
+#[derive(Debug, Clone)]
+pub struct PlanStep {
+    pub package: Package,
+    pub action: Action,
+}
+
+/// An installed package that is removed because a package in the plan replaces it
+#[derive(Debug, Clone)]
+pub struct Removal {
+    pub package: Package,
+    /// The name of the package taking over
+    pub replaced_by: String,
+}
+
+/// A complete, consistent set of packages to install, dependencies first
+///
+/// Removals happen before any step is installed, so the packages taking over can install the
+/// same files.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+    pub removals: Vec<Removal>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty() && self.removals.is_empty()
+    }
+
+    /// What the packages in the plan suggest that isn't installed and isn't part of the plan
+    ///
+    /// # Arguments
+    /// * `installed` - The installed packages (`repo.yml`) from before the plan
+    ///
+    /// # Returns
+    /// * `Vec<(String, String)>` - The suggested packages with the package suggesting each one, sorted
+    pub fn suggestions(&self, installed: &Repository) -> Vec<(String, String)> {
+        let removed: Vec<&str> = self.removals.iter().map(|removal| removal.package.name.as_str()).collect();
+        let present: Vec<&Package> = self.steps.iter().map(|step| &step.package)
+            .chain(installed.packages.values().filter(|package| !removed.contains(&package.name.as_str())))
+            .collect();
+
+        let mut suggestions = Vec::new();
+
+        for step in &self.steps {
+            for name in step.package.suggests.keys() {
+                let is_present = present.iter().any(|package| package.name == *name || package.provides.contains_key(name));
+
+                if !is_present {
+                    suggestions.push((name.clone(), step.package.name.clone()));
+                }
+            }
+        }
+
+        suggestions.sort();
+        suggestions.dedup();
+        suggestions
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for removal in &self.removals {
+            writeln!(f, "remove {} {} (replaced by {})", removal.package.name, removal.package.display_version(), removal.replaced_by)?;
+        }
+
+        for step in &self.steps {
+            match &step.action {
+                Action::Install => writeln!(f, "install {} {}", step.package.name, step.package.display_version())?,
+                Action::Reinstall => writeln!(f, "reinstall {} {}", step.package.name, step.package.display_version())?,
+                Action::Upgrade { from } => writeln!(f, "upgrade {} {} -> {}", step.package.name, from, step.package.display_version())?,
+                Action::Downgrade { from } => writeln!(f, "downgrade {} {} -> {}", step.package.name, from, step.package.display_version())?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A version of a package that could be picked
+#[derive(Debug, Clone)]
+struct Candidate {
+    package: Package,
+    installed: bool,
+}
+
+/// A constraint on a package and where it came from
+#[derive(Debug, Clone)]
+struct Requirement {
+    name: String,
+    constraint: Constraint,
+    from_available: bool,
+    required_by: Option<String>,
+    /// A recommendation, which is left out if it can't be met
+    optional: bool,
+    /// Whether the recommendations of packages picked for this are followed
+    recommends: bool,
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.required_by {
+            Some(parent) if self.optional => write!(f, "{} recommends {} {}", parent, self.name, self.constraint),
+            Some(parent) => write!(f, "{} requires {} {}", parent, self.name, self.constraint),
+            None => write!(f, "{} {} was requested", self.name, self.constraint),
+        }
+    }
+}
+
+/// The state of a search: what has been picked and every requirement seen for each package
+#[derive(Clone, Default)]
+struct State {
+    chosen: BTreeMap<String, Candidate>,
+    requirements: HashMap<String, Vec<Requirement>>,
+}
+
+/// Computes an install plan for the requested packages
+///
+/// Every dependency constraint is checked against the installed database and the available
+/// packages (usually `cache.yml`) before anything is touched. Installed versions are kept
+/// whenever they satisfy everything that depends on them. A dependency can also be satisfied by a
+/// package that provides it, a real package of that name is preferred. Packages that conflict
+/// with each other are never picked together, and installed packages that a new package replaces
+/// are removed. If no consistent set of packages exists the error explains which requirements
+/// could not be met.
+///
+/// # Arguments
+/// * `installed` - The installed packages (`repo.yml`)
+/// * `available` - The packages that can be installed (`cache.yml`)
+/// * `requests` - The packages the caller asked for
+pub fn resolve(installed: &Repository, available: &Repository, requests: &[Request]) -> Result<Plan> {
+    let pending: Vec<Requirement> = requests.iter().map(|request| Requirement {
+        name: request.name.clone(),
+        constraint: request.constraint.clone(),
+        from_available: request.from_available,
+        required_by: None,
+        optional: false,
+        recommends: request.recommends,
+    }).collect();
+
+    let state = match search(installed, available, State::default(), pending) {
+        Ok(state) => state,
+        Err(explanation) => {
+            let package = requests.first().map(|request| request.name.clone()).unwrap_or_default();
+
+            return Err(Error::dependency(package, format!("no solution found:\n  {}", explanation.join("\n  "))));
+        }
+    };
+
+    order(installed, &state)
+}
+
+/// Works through the pending requirements, trying each candidate in turn and backtracking when a
+/// choice leads to a conflict
+///
+/// # Returns
+/// * `Result<State, Vec<String>>` - The final state, or an explanation of why there is none
+fn search(installed: &Repository, available: &Repository, mut state: State, mut pending: Vec<Requirement>) -> std::result::Result<State, Vec<String>> {
+    // requirements are handled in the order they were found
+    pending.reverse();
+
+    while let Some(requirement) = pending.pop() {
+        // a recommendation that can't be met is left out, so keep what things looked like without it
+        let fallback = requirement.optional.then(|| (state.clone(), pending.clone()));
+
+        state.requirements.entry(requirement.name.clone()).or_default().push(requirement.clone());
+
+        // already picked, so it just has to fit
+        if let Some(candidate) = state.chosen.get(&requirement.name) {
+            let fits = requirement.constraint.matches_str(&candidate.package.version)
+                && (!requirement.from_available || !candidate.installed);
+
+            if fits {
+                continue;
+            }
+
+            let explanation = conflict(&state, &requirement.name, Some(&candidate.package.display_version()));
+
+            return give_up(installed, available, fallback, explanation);
+        }
+
+        // a package picked earlier may already provide it
+        let provided = !requirement.from_available && state.chosen.values()
+            .any(|candidate| satisfies(&candidate.package, &requirement.name, &requirement.constraint));
+
+        if provided {
+            continue;
+        }
+
+        let candidates = candidates(installed, available, &state, &requirement.name);
+
+        if candidates.is_empty() {
+            let explanation = conflict(&state, &requirement.name, None);
+
+            return give_up(installed, available, fallback, explanation);
+        }
+
+        let mut explanation = Vec::new();
+
+        // try each candidate with the rest of the requirements, the first that works wins
+        'candidates: for candidate in candidates {
+            let mut next_state = state.clone();
+            let mut next_pending = pending.clone();
+            next_pending.reverse();
+
+            let mut dependencies: Vec<_> = candidate.package.dependencies.iter().map(|dependency| (dependency, false)).collect();
+            dependencies.sort();
+
+            // installed packages keep what they have, only new ones bring their recommendations along
+            if requirement.recommends && !candidate.installed {
+                let mut recommends: Vec<_> = candidate.package.recommends.iter().map(|recommend| (recommend, true)).collect();
+                recommends.sort();
+                dependencies.extend(recommends);
+            }
+
+            for ((name, constraint), optional) in dependencies {
+                let constraint = match Constraint::from_string(constraint.clone()) {
+                    Ok(constraint) => constraint,
+                    Err(err) => {
+                        explanation.push(format!("{} {} has an invalid dependency on {}: {}", candidate.package.name, candidate.package.display_version(), name, err));
+                        continue 'candidates;
+                    }
+                };
+
+                next_pending.push(Requirement {
+                    name: name.clone(),
+                    constraint,
+                    from_available: false,
+                    required_by: Some(format!("{} {}", candidate.package.name, candidate.package.display_version())),
+                    optional,
+                    recommends: requirement.recommends,
+                });
+            }
+
+            next_state.chosen.insert(candidate.package.name.clone(), candidate);
+
+            match search(installed, available, next_state, next_pending) {
+                Ok(state) => return Ok(state),
+                Err(reasons) => {
+                    for reason in reasons {
+                        if !explanation.contains(&reason) {
+                            explanation.push(reason);
+                        }
+                    }
+                }
+            }
+        }
+
+        return give_up(installed, available, fallback, explanation);
+    }
+
+    check_selection(installed, &state)?;
+
+    Ok(state)
+}
+
+/// Carries on without a recommendation that can't be met, or fails for anything else
+///
+/// # Arguments
+/// * `fallback` - The state and pending requirements from before the recommendation, if it was one
+/// * `explanation` - Why the requirement can't be met
+fn give_up(installed: &Repository, available: &Repository, fallback: Option<(State, Vec<Requirement>)>, explanation: Vec<String>) -> std::result::Result<State, Vec<String>> {
+    match fallback {
+        Some((state, mut pending)) => {
+            // pending is kept in reverse, search expects it in order
+            pending.reverse();
+
+            search(installed, available, state, pending)
+        }
+        None => Err(explanation),
+    }
+}
+
+/// The versions of a package that satisfy every requirement seen so far, best first
+///
+/// The package itself comes before packages that provide it, and installed packages before
+/// available ones.
+fn candidates(installed: &Repository, available: &Repository, state: &State, name: &str) -> Vec<Candidate> {
+    let requirements = state.requirements.get(name).cloned().unwrap_or_default();
+    let from_available = requirements.iter().any(|requirement| requirement.from_available);
+
+    let mut candidates = Vec::new();
+
+    // keep what is installed when possible, so a dependency doesn't cause needless changes
+    if !from_available {
+        if let Some(package) = installed.get_package(name.to_string()) {
+            candidates.push(Candidate { package: package.clone(), installed: true });
+        }
+    }
+
+    if let Some(package) = available.get_package(name.to_string()) {
+        let duplicate = candidates.iter().any(|candidate| &candidate.package == package);
+
+        if !duplicate {
+            candidates.push(Candidate { package: package.clone(), installed: false });
+        }
+    }
+
+    // providers that weren't picked for something else yet
+    if !from_available {
+        for (repository, is_installed) in [(installed, true), (available, false)] {
+            let mut providers: Vec<&Package> = repository.packages.values()
+                .filter(|package| package.name != name && package.provides.contains_key(name))
+                .filter(|package| !state.chosen.contains_key(&package.name))
+                .collect();
+            providers.sort_by(|a, b| a.name.cmp(&b.name));
+
+            for package in providers {
+                let duplicate = candidates.iter().any(|candidate| &candidate.package == package);
+
+                if !duplicate {
+                    candidates.push(Candidate { package: package.clone(), installed: is_installed });
+                }
+            }
+        }
+    }
+
+    candidates.retain(|candidate| {
+        requirements.iter().all(|requirement| satisfies(&candidate.package, name, &requirement.constraint))
+    });
+
+    candidates
+}
+
+/// The installed packages that packages picked for installation replace, with the package
+/// replacing each one, sorted by name
+fn replaced(installed: &Repository, state: &State) -> Vec<(Package, String)> {
+    let mut replaced = Vec::new();
+
+    for candidate in state.chosen.values().filter(|candidate| !candidate.installed) {
+        let mut packages: Vec<&Package> = installed.packages.values()
+            .filter(|package| package.name != candidate.package.name && replaces(&candidate.package, package))
+            .collect();
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for package in packages {
+            if !replaced.iter().any(|(replaced, _): &(Package, String)| replaced.name == package.name) {
+                replaced.push((package.clone(), candidate.package.name.clone()));
+            }
+        }
+    }
+
+    replaced.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    replaced
+}
+
+/// Checks a complete selection against conflicts and replacements
+///
+/// Conflicts between packages that are already installed are left alone, only new packages are
+/// checked. A replaced package can't be kept for something else, and installed packages that
+/// depend on it need something else to provide it.
+fn check_selection(installed: &Repository, state: &State) -> std::result::Result<(), Vec<String>> {
+    let replaced = replaced(installed, state);
+    let removed: HashSet<&str> = replaced.iter().map(|(package, _)| package.name.as_str()).collect();
+
+    for (package, replaced_by) in &replaced {
+        if state.chosen.contains_key(&package.name) {
+            return Err(vec![format!("{} {} is replaced by {}, but {}", package.name, package.display_version(), replaced_by,
+                                    requirements_of(state, &package.name))]);
+        }
+    }
+
+    // everything that is installed once the plan is done, with whether it is new
+    let mut result: Vec<(&Package, bool)> = state.chosen.values()
+        .map(|candidate| (&candidate.package, !candidate.installed))
+        .collect();
+    result.extend(installed.packages.values()
+        .filter(|package| !state.chosen.contains_key(&package.name) && !removed.contains(package.name.as_str()))
+        .map(|package| (package, false)));
+    result.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+
+    for (package, new) in &result {
+        let mut conflicts: Vec<_> = package.conflicts.iter().collect();
+        conflicts.sort();
+
+        for (name, constraint) in conflicts {
+            let Ok(constraint) = Constraint::from_string(constraint.clone()) else {
+                return Err(vec![format!("{} {} has an invalid conflict with {}", package.name, package.display_version(), name)]);
+            };
+
+            let other = result.iter().find(|(other, other_new)| {
+                other.name != package.name && (*new || *other_new) && satisfies(other, name, &constraint)
+            });
+
+            if let Some((other, _)) = other {
+                return Err(vec![format!("{} {} conflicts with {} {}, but both would be installed",
+                                        package.name, package.display_version(), other.name, other.display_version())]);
+            }
+        }
+    }
+
+    // kept packages that need a replaced one have to get it from somewhere else
+    for (package, new) in &result {
+        if *new {
+            continue;
+        }
+
+        let mut dependencies: Vec<_> = package.dependencies.iter().filter(|(name, _)| removed.contains(name.as_str())).collect();
+        dependencies.sort();
+
+        for (name, constraint) in dependencies {
+            let constraint = Constraint::from_string(constraint.clone()).unwrap_or_else(|_| Constraint::any());
+
+            if !result.iter().any(|(other, _)| satisfies(other, name, &constraint)) {
+                let replaced_by = replaced.iter().find(|(replaced, _)| &replaced.name == name).map(|(_, by)| by.as_str()).unwrap_or_default();
+
+                return Err(vec![format!("{} {} requires {} {}, but it would be replaced by {}",
+                                        package.name, package.display_version(), name, constraint, replaced_by)]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists what required a package, for explanations
+fn requirements_of(state: &State, name: &str) -> String {
+    state.requirements.get(name)
+        .map(|requirements| requirements.iter().map(|requirement| requirement.to_string()).collect::<Vec<_>>().join(", "))
+        .unwrap_or_default()
+}
+
+/// Explains why no version of a package fits
+fn conflict(state: &State, name: &str, chosen: Option<&str>) -> Vec<String> {
+    let mut explanation: Vec<String> = state.requirements.get(name)
+        .map(|requirements| requirements.iter().map(|requirement| requirement.to_string()).collect())
+        .unwrap_or_default();
+
+    match chosen {
+        Some(version) => explanation.push(format!("but {} {} was already selected", name, version)),
+        None => explanation.push(format!("but no installed or available version of {} satisfies that", name)),
+    }
+
+    vec![explanation.join(", ")]
+}
+
+/// Turns the chosen packages into install steps with dependencies before the packages that need
+/// them
+///
+/// Packages that depend on each other in a cycle can't all go after their dependencies, so the
+/// members of a cycle are installed in whatever order the search reaches them.
+fn order(installed: &Repository, state: &State) -> Result<Plan> {
+    let mut plan = Plan::default();
+    let mut visited = HashSet::new();
+
+    for name in state.chosen.keys() {
+        visit(installed, state, name, &mut visited, &mut plan)?;
+    }
+
+    plan.removals = replaced(installed, state).into_iter()
+        .map(|(package, replaced_by)| Removal { package, replaced_by })
+        .collect();
+
+    Ok(plan)
+}
+
+fn visit(installed: &Repository, state: &State, name: &str, visited: &mut HashSet<String>, plan: &mut Plan) -> Result<()> {
+    let candidate = match state.chosen.get(name) {
+        Some(candidate) => candidate,
+        None => return Ok(()),
+    };
+
+    // kept packages are already in place, so they can't be part of an ordering problem
+    if candidate.installed {
+        return Ok(());
+    }
+
+    // a package that is still being visited is part of a cycle, it is placed once its visit ends
+    if !visited.insert(name.to_string()) {
+        return Ok(());
+    }
+
+    let mut dependencies: Vec<_> = candidate.package.dependencies.keys().collect();
+    dependencies.sort();
+
+    for dependency in dependencies {
+        // a virtual dependency is ordered after whatever provides it
+        let dependency = match state.chosen.contains_key(dependency) {
+            true => dependency,
+            false => state.chosen.values()
+                .find(|candidate| candidate.package.provides.contains_key(dependency))
+                .map(|candidate| &candidate.package.name)
+                .unwrap_or(dependency),
+        };
+
+        visit(installed, state, dependency, visited, plan)?;
+    }
+
+    let action = match installed.get_package(name.to_string()) {
+        None => Action::Install,
+        Some(current) => {
+            let new = candidate.package.full_version()?;
+            let old = current.full_version()?;
+
+            if new > old {
+                Action::Upgrade { from: current.display_version() }
+            } else if new < old {
+                Action::Downgrade { from: current.display_version() }
+            } else {
+                Action::Reinstall
+            }
+        }
+    };
+
+    plan.steps.push(PlanStep { package: candidate.package.clone(), action });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constraint(constraint: &str) -> Constraint {
+        Constraint::from_string(constraint.to_string()).unwrap()
+    }
+
+    fn package(name: &str, version: &str, dependencies: &[(&str, &str)]) -> Package {
+        let dependencies = dependencies.iter().map(|(name, constraint)| (name.to_string(), constraint.to_string())).collect();
+
+        Package::new(name.to_string(), String::new(), version.to_string(), dependencies, "MIT".to_string(), Vec::new())
+    }
+
+    fn repository(packages: Vec<Package>) -> Repository {
+        let mut repository = Repository::new(true);
+
+        for package in packages {
+            repository.add_package(package);
+        }
+
+        repository
+    }
+
+    /// The steps of a plan as "name version", in install order
+    fn steps(plan: &Plan) -> Vec<String> {
+        plan.steps.iter().map(|step| format!("{} {}", step.package.name, step.package.version)).collect()
+    }
+
+    #[test]
+    fn bare_version_is_a_minimum() {
+        let constraint = constraint("1.2.0");
+
+        assert!(constraint.matches_str("1.2.0"));
+        assert!(constraint.matches_str("3.0.0"));
+        assert!(!constraint.matches_str("1.1.9"));
+    }
+
+    #[test]
+    fn comparators_all_have_to_match() {
+        let constraint = constraint(">=1.2, <2");
+
+        assert!(constraint.matches_str("1.2.0"));
+        assert!(constraint.matches_str("1.9.9"));
+        assert!(!constraint.matches_str("1.1.0"));
+        assert!(!constraint.matches_str("2.0.0"));
+
+        assert!(self::constraint(">1.0.0").matches_str("1.0.1"));
+        assert!(!self::constraint(">1.0.0").matches_str("1.0.0"));
+        assert!(self::constraint("<=1.0.0").matches_str("1.0.0"));
+        assert!(!self::constraint("<=1.0.0").matches_str("1.0.1"));
+    }
+
+    #[test]
+    fn caret_keeps_the_first_non_zero_part() {
+        assert!(constraint("^1.4").matches_str("1.9.0"));
+        assert!(!constraint("^1.4").matches_str("1.3.0"));
+        assert!(!constraint("^1.4").matches_str("2.0.0"));
+
+        assert!(constraint("^0.3.1").matches_str("0.3.9"));
+        assert!(!constraint("^0.3.1").matches_str("0.4.0"));
+
+        assert!(constraint("^0.0.3").matches_str("0.0.3"));
+        assert!(!constraint("^0.0.3").matches_str("0.0.4"));
+
+        assert!(constraint("^0").matches_str("0.9.0"));
+        assert!(!constraint("^0").matches_str("1.0.0"));
+    }
+
+    #[test]
+    fn tilde_allows_patch_changes() {
+        assert!(constraint("~1.2.3").matches_str("1.2.9"));
+        assert!(!constraint("~1.2.3").matches_str("1.3.0"));
+        assert!(!constraint("~1.2.3").matches_str("1.2.2"));
+
+        assert!(constraint("~1").matches_str("1.5.0"));
+        assert!(!constraint("~1").matches_str("2.0.0"));
+    }
+
+    #[test]
+    fn upper_bounds_at_the_largest_numbers_do_not_overflow() {
+        let max = u64::MAX;
+
+        // the number before the largest one goes up instead
+        assert!(constraint(&format!("~1.{}", max)).matches_str(&format!("1.{}.7", max)));
+        assert!(!constraint(&format!("~1.{}", max)).matches_str("2.0.0"));
+        assert!(constraint(&format!("^0.0.{}", max)).matches_str(&format!("0.0.{}", max)));
+        assert!(!constraint(&format!("^0.0.{}", max)).matches_str("0.1.0"));
+
+        // and with nothing before it there is no upper bound at all
+        assert!(constraint(&format!("^{}", max)).matches_str(&format!("{}.{}.{}", max, max, max)));
+        assert!(constraint(&format!("={}", max)).matches_str(&format!("{}.3.0", max)));
+        assert!(constraint(&format!("~{}.{}", max, max)).matches_str(&format!("{}.{}.0", max, max)));
+        assert!(!constraint(&format!("^{}", max)).matches_str("1.0.0"));
+
+        assert_eq!(bump(1, Some(2), Some(max)), Some(SemVer::new(1, 3, 0)));
+        assert_eq!(bump(max, Some(max), Some(max)), None);
+    }
+
+    #[test]
+    fn equals_matches_every_version_it_leaves_open() {
+        assert!(constraint("=1.0.0").matches_str("1.0.0"));
+        assert!(!constraint("=1.0.0").matches_str("1.0.1"));
+
+        assert!(constraint("==1.2").matches_str("1.2.7"));
+        assert!(!constraint("=1.2").matches_str("1.3.0"));
+    }
+
+    #[test]
+    fn empty_and_star_match_anything() {
+        for text in ["", "*", " * "] {
+            let constraint = constraint(text);
+
+            assert!(constraint.is_any());
+            assert_eq!(constraint.to_string(), "*");
+            assert!(constraint.matches_str("0.0.1"));
+            assert!(constraint.matches_str("2.0.0-rc.1"));
+        }
+    }
+
+    #[test]
+    fn pre_releases_have_to_be_named() {
+        assert!(!constraint("<2").matches_str("2.0.0-rc.1"));
+        assert!(!constraint(">=1.2, <2").matches_str("2.0.0-rc.1"));
+        assert!(!constraint("^1.4").matches_str("2.0.0-rc.1"));
+        assert!(!constraint("^1.4").matches_str("1.5.0-alpha"));
+
+        assert!(constraint(">=2.0.0-beta").matches_str("2.0.0-rc.1"));
+        assert!(!constraint(">=2.0.0-beta").matches_str("2.0.0-alpha"));
+        assert!(!constraint(">=2.0.0-beta").matches_str("2.1.0-alpha"));
+        assert!(constraint(">=2.0.0-beta").matches_str("2.1.0"));
+        assert!(constraint("=1.0.0-rc.1").matches_str("1.0.0-rc.1"));
+    }
+
+    #[test]
+    fn invalid_constraints_are_rejected() {
+        for text in ["abc", ">=", "1.2.3.4", "=>1.0.0", "!1.0.0", "1.x"] {
+            assert!(Constraint::from_string(text.to_string()).is_err(), "{} should be invalid", text);
+        }
+
+        assert!(!constraint("1.0.0").matches_str("not a version"));
+    }
+
+    #[test]
+    fn dependencies_are_installed_first() {
+        let installed = repository(Vec::new());
+        let available = repository(vec![
+            package("app", "1.0.0", &[("lib", "^1")]),
+            package("lib", "1.2.0", &[("base", "*")]),
+            package("base", "1.0.0", &[]),
+        ]);
+
+        let plan = resolve(&installed, &available, &[Request::new("app".to_string())]).unwrap();
+
+        assert_eq!(steps(&plan), ["base 1.0.0", "lib 1.2.0", "app 1.0.0"]);
+        assert!(plan.steps.iter().all(|step| step.action == Action::Install));
+    }
+
+    #[test]
+    fn installed_versions_are_kept_when_they_fit() {
+        let installed = repository(vec![package("lib", "1.0.0", &[])]);
+        let available = repository(vec![
+            package("app", "1.0.0", &[("lib", ">=1")]),
+            package("lib", "2.0.0", &[]),
+        ]);
+
+        let plan = resolve(&installed, &available, &[Request::new("app".to_string())]).unwrap();
+
+        assert_eq!(steps(&plan), ["app 1.0.0"]);
+    }
+
+    #[test]
+    fn backtracks_when_a_kept_version_stops_fitting() {
+        // lib 1.0.0 is tried first, until tool asks for lib ^2
+        let installed = repository(vec![package("lib", "1.0.0", &[])]);
+        let available = repository(vec![
+            package("app", "1.0.0", &[("lib", ">=1"), ("tool", "*")]),
+            package("tool", "1.0.0", &[("lib", "^2")]),
+            package("lib", "2.0.0", &[]),
+        ]);
+
+        let plan = resolve(&installed, &available, &[Request::new("app".to_string())]).unwrap();
+
+        assert_eq!(steps(&plan), ["lib 2.0.0", "tool 1.0.0", "app 1.0.0"]);
+        assert_eq!(plan.steps[0].action, Action::Upgrade { from: "1.0.0".to_string() });
+    }
+
+    #[test]
+    fn backtracks_to_the_next_provider_on_a_conflict() {
+        let mut gawk = package("gawk", "5.0.0", &[]);
+        gawk.provides.insert("awk".to_string(), Some("5.0.0".to_string()));
+        gawk.conflicts.insert("busybox".to_string(), "*".to_string());
+
+        let mut mawk = package("mawk", "1.3.4", &[]);
+        mawk.provides.insert("awk".to_string(), Some("1.3.4".to_string()));
+
+        let installed = repository(vec![package("busybox", "1.0.0", &[])]);
+        let available = repository(vec![package("script", "1.0.0", &[("awk", ">=1")]), gawk, mawk]);
+
+        let plan = resolve(&installed, &available, &[Request::new("script".to_string())]).unwrap();
+
+        assert_eq!(steps(&plan), ["mawk 1.3.4", "script 1.0.0"]);
+    }
+
+    #[test]
+    fn explains_conflicting_requirements() {
+        let installed = repository(Vec::new());
+        let available = repository(vec![
+            package("app", "1.0.0", &[("lib", "^1"), ("tool", "*")]),
+            package("tool", "1.0.0", &[("lib", "^2")]),
+            package("lib", "1.0.0", &[]),
+        ]);
+
+        let err = resolve(&installed, &available, &[Request::new("app".to_string())]).unwrap_err().to_string();
+
+        assert!(err.contains("tool 1.0.0 requires lib ^2"), "{}", err);
+        assert!(err.contains("but lib 1.0.0 was already selected"), "{}", err);
+    }
+
+    #[test]
+    fn unmet_recommendations_are_left_out() {
+        let mut app = package("app", "1.0.0", &[]);
+        app.recommends.insert("docs".to_string(), "^2".to_string());
+        app.recommends.insert("extras".to_string(), "*".to_string());
+
+        let installed = repository(Vec::new());
+        let available = repository(vec![app, package("docs", "1.0.0", &[]), package("extras", "1.0.0", &[])]);
+
+        let plan = resolve(&installed, &available, &[Request::new("app".to_string())]).unwrap();
+
+        assert_eq!(steps(&plan), ["app 1.0.0", "extras 1.0.0"]);
+    }
+
+    #[test]
+    fn cycles_are_ordered() {
+        let installed = repository(Vec::new());
+        let available = repository(vec![
+            package("a", "1.0.0", &[("b", "*")]),
+            package("b", "1.0.0", &[("a", "*"), ("c", "*")]),
+            package("c", "1.0.0", &[]),
+        ]);
+
+        let plan = resolve(&installed, &available, &[Request::new("a".to_string())]).unwrap();
+
+        assert_eq!(steps(&plan), ["c 1.0.0", "b 1.0.0", "a 1.0.0"]);
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::Read;
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256, Sha512};
+use crate::error::{Error, Result};
+
+/// Kept apart from Ed25519ph signatures made for anything else with the same key
+const CONTEXT: &[u8] = b"comet";
+
+/// The public keys whose signatures comet accepts
+///
+/// Keys are read from `*.pub` files in the trusted keys directory. Each key is known by its id, the
+/// first 8 bytes of the SHA-256 of the key in hex, which is also written into every signature so
+/// the right key can be picked when verifying.
+///
+/// Signatures are Ed25519ph, over the SHA-512 of the signed file, so a star is checked as it is
+/// read instead of being loaded into memory whole.
+pub struct TrustedKeys {
+    keys: Vec<(String, VerifyingKey)>,
+}
+
+impl TrustedKeys {
+    pub fn new() -> TrustedKeys {
+        TrustedKeys { keys: Vec::new() }
+    }
+
+    /// Loads every `*.pub` file in a directory, a missing directory means no trusted keys
+    ///
+    /// # Arguments
+    /// * `dir` - The trusted keys directory
+    pub fn from_dir(dir: &str) -> Result<TrustedKeys> {
+        let mut trusted = TrustedKeys::new();
+
+        if !Path::new(dir).exists() {
+            return Ok(trusted);
+        }
+
+        let entries = std::fs::read_dir(dir)
+            .map_err(|err| Error::io(format!("Error while reading trusted keys directory {}", dir), err))?;
+
+        let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+        paths.sort();
+
+        for path in paths {
+            if path.extension().map(|extension| extension != "pub").unwrap_or(true) {
+                continue;
+            }
+
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|err| Error::io(format!("Error while reading key {}", path.display()), err))?;
+
+            trusted.add(public_key_from_string(&contents, &path.to_string_lossy())?);
+        }
+
+        Ok(trusted)
+    }
+
+    pub fn add(&mut self, key: VerifyingKey) {
+        self.keys.push((key_id(&key), key));
+    }
+
+    /// Whether or not any keys are trusted, if none are signatures aren't checked
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// A hash of every trusted key, which changes whenever a key is added or removed
+    ///
+    /// It is stored with whatever was checked against the keys, so it can tell whether that was
+    /// checked against the keys trusted now.
+    pub fn fingerprint(&self) -> String {
+        let mut ids: Vec<&str> = self.keys.iter().map(|(id, _)| id.as_str()).collect();
+        ids.sort();
+        ids.dedup();
+
+        format!("{:x}", Sha256::digest(ids.join(" ").as_bytes()))
+    }
+
+    /// Checks a detached signature against the trusted keys
+    ///
+    /// # Arguments
+    /// * `subject` - What is being verified, used in errors
+    /// * `data` - The signed data, read to the end
+    /// * `signature` - The contents of the signature file (see `sign`)
+    pub fn verify(&self, subject: &str, data: impl Read, signature: &str) -> Result<()> {
+        let invalid = |message: String| Error::Signature { subject: subject.to_string(), message };
+
+        let (id, signature) = signature.trim().split_once(' ')
+            .ok_or_else(|| invalid("the signature is malformed".to_string()))?;
+
+        let key = match self.keys.iter().find(|(key_id, _)| key_id == id) {
+            Some((_, key)) => key,
+            None => return Err(invalid(format!("it is signed by key {}, which is not trusted", id))),
+        };
+
+        let signature = STANDARD.decode(signature.trim())
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| invalid("the signature is malformed".to_string()))?;
+
+        key.verify_prehashed(hash(subject, data)?, Some(CONTEXT), &signature)
+            .map_err(|_| invalid(format!("the signature does not match key {}", id)))
+    }
+}
+
+impl Default for TrustedKeys {
+    fn default() -> TrustedKeys {
+        TrustedKeys::new()
+    }
+}
+
+/// The id of a public key, the first 8 bytes of its SHA-256 in hex
+pub fn key_id(key: &VerifyingKey) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+
+    hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Generates a new key pair
+///
+/// # Returns
+/// * `Result<(String, String)>` - The contents of the secret key file and the public key file
+pub fn generate_key() -> Result<(String, String)> {
+    let mut seed = [0u8; 32];
+
+    getrandom::getrandom(&mut seed)
+        .map_err(|err| Error::io("Error while generating a key", std::io::Error::from(err)))?;
+
+    let secret = SigningKey::from_bytes(&seed);
+    let public = secret.verifying_key();
+    let id = key_id(&public);
+
+    let secret_file = format!("# comet secret key {}\n{}\n", id, STANDARD.encode(secret.to_bytes()));
+    let public_file = format!("# comet public key {}\n{}\n", id, STANDARD.encode(public.to_bytes()));
+
+    Ok((secret_file, public_file))
+}
+
+/// Signs data with a secret key
+///
+/// # Arguments
+/// * `secret_key` - The contents of a secret key file (see `generate_key`)
+/// * `subject` - What is being signed, used in errors
+/// * `data` - The data to sign, read to the end
+///
+/// # Returns
+/// * `Result<String>` - The contents of the detached signature file, `<key id> <signature>`
+pub fn sign(secret_key: &str, subject: &str, data: impl Read) -> Result<String> {
+    let bytes = key_bytes(secret_key, "secret key")?;
+    let secret = SigningKey::from_bytes(&bytes);
+    let signature = secret.sign_prehashed(hash(subject, data)?, Some(CONTEXT))
+        .map_err(|err| Error::parse(format!("Error while signing {}", subject), err))?;
+
+    Ok(format!("{} {}\n", key_id(&secret.verifying_key()), STANDARD.encode(signature.to_bytes())))
+}
+
+/// The SHA-512 of everything read from `data`, which is what gets signed
+fn hash(subject: &str, mut data: impl Read) -> Result<Sha512> {
+    let mut hasher = Sha512::new();
+
+    std::io::copy(&mut data, &mut hasher)
+        .map_err(|err| Error::io(format!("Error while reading {}", subject), err))?;
+
+    Ok(hasher)
+}
+
+/// Parses the contents of a public key file
+///
+/// # Arguments
+/// * `contents` - The contents of the file
+/// * `name` - The name of the file, used in errors
+pub fn public_key_from_string(contents: &str, name: &str) -> Result<VerifyingKey> {
+    let bytes = key_bytes(contents, name)?;
+
+    VerifyingKey::from_bytes(&bytes).map_err(|err| Error::parse(format!("Invalid public key {}", name), err))
+}
+
+/// Decodes the base64 key in a key file, skipping comment lines
+fn key_bytes(contents: &str, name: &str) -> Result<[u8; 32]> {
+    let invalid = || Error::Parse {
+        context: format!("Invalid key {}", name),
+        source: None,
+    };
+
+    let line = contents.lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(invalid)?;
+
+    let bytes = STANDARD.decode(line).map_err(|err| Error::parse(format!("Invalid key {}", name), err))?;
+
+    bytes.try_into().map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new key pair, and the trusted keys holding just its public key
+    fn trusted() -> (String, TrustedKeys) {
+        let (secret, public) = generate_key().unwrap();
+
+        let mut keys = TrustedKeys::new();
+        keys.add(public_key_from_string(&public, "test.pub").unwrap());
+
+        (secret, keys)
+    }
+
+    fn is_signature_error(result: Result<()>) -> bool {
+        matches!(result, Err(Error::Signature { .. }))
+    }
+
+    #[test]
+    fn good_signature_verifies() {
+        let (secret, keys) = trusted();
+        let signature = sign(&secret, "repo.yml", b"packages: {}\n".as_slice()).unwrap();
+
+        keys.verify("repo.yml", b"packages: {}\n".as_slice(), &signature).unwrap();
+    }
+
+    #[test]
+    fn tampered_data_is_refused() {
+        let (secret, keys) = trusted();
+        let signature = sign(&secret, "repo.yml", b"packages: {}\n".as_slice()).unwrap();
+
+        assert!(is_signature_error(keys.verify("repo.yml", b"packages: {evil: {}}\n".as_slice(), &signature)));
+    }
+
+    #[test]
+    fn tampered_signature_is_refused() {
+        let (secret, keys) = trusted();
+        let signature = sign(&secret, "repo.yml", b"packages: {}\n".as_slice()).unwrap();
+
+        let (id, encoded) = signature.trim().split_once(' ').unwrap();
+        let mut bytes = STANDARD.decode(encoded).unwrap();
+        bytes[0] ^= 1;
+        let tampered = format!("{} {}", id, STANDARD.encode(bytes));
+
+        assert!(is_signature_error(keys.verify("repo.yml", b"packages: {}\n".as_slice(), &tampered)));
+        assert!(is_signature_error(keys.verify("repo.yml", b"packages: {}\n".as_slice(), "not a signature")));
+    }
+
+    #[test]
+    fn untrusted_key_is_refused() {
+        let (_, keys) = trusted();
+        let (other, _) = trusted();
+        let signature = sign(&other, "repo.yml", b"packages: {}\n".as_slice()).unwrap();
+
+        let Err(Error::Signature { message, .. }) = keys.verify("repo.yml", b"packages: {}\n".as_slice(), &signature) else {
+            panic!("a signature by an untrusted key was accepted");
+        };
+
+        assert!(message.contains("not trusted"));
+    }
+
+    #[test]
+    fn keys_are_loaded_from_pub_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (secret, public) = generate_key().unwrap();
+
+        std::fs::write(dir.path().join("galaxy.pub"), public).unwrap();
+        std::fs::write(dir.path().join("galaxy.key"), &secret).unwrap();
+        std::fs::write(dir.path().join("README"), "not a key").unwrap();
+
+        let keys = TrustedKeys::from_dir(&dir.path().to_string_lossy()).unwrap();
+        let signature = sign(&secret, "comet.star", b"star".as_slice()).unwrap();
+
+        keys.verify("comet.star", b"star".as_slice(), &signature).unwrap();
+        assert!(TrustedKeys::from_dir(&dir.path().join("missing").to_string_lossy()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_key_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("broken.pub"), "# comet public key\nnot base64!\n").unwrap();
+
+        assert!(matches!(TrustedKeys::from_dir(&dir.path().to_string_lossy()), Err(Error::Parse { .. })));
+
+        // valid base64, but not 32 bytes
+        assert!(public_key_from_string(&format!("# comet public key\n{}\n", STANDARD.encode([1u8; 16])), "short.pub").is_err());
+        assert!(public_key_from_string("# only a comment\n", "empty.pub").is_err());
+        assert!(sign("not a key", "repo.yml", b"".as_slice()).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use tar::Archive;
+use crate::error::{Error, Result};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How the tar archive inside a star is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// A plain tar archive, like every star built before compression existed
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Works out the compression of a star from its first few bytes
+    ///
+    /// Anything that isn't a known compressed format is treated as a plain tar archive
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn from_string(name: &str) -> Result<Compression> {
+        match name.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "xz" => Ok(Compression::Xz),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            _ => Err(Error::InvalidArgument(format!("Unknown compression {}, expected none, gzip, xz or zstd", name))),
+        }
+    }
+
+    /// The compression level used when none is given
+    pub fn default_level(&self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 6,
+            Compression::Xz => 6,
+            Compression::Zstd => 19,
+        }
+    }
+
+    /// The compression levels the codec accepts
+    pub fn levels(&self) -> RangeInclusive<u32> {
+        match self {
+            Compression::None => 0..=0,
+            Compression::Gzip => 0..=9,
+            Compression::Xz => 0..=9,
+            Compression::Zstd => 1..=22,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Compression> {
+        Compression::from_string(name)
+    }
+}
+
+/// Opens a star for reading, decompressing it if needed
+///
+/// # Arguments
+/// * `file_path` - The star file
+///
+/// # Returns
+/// * `Result<Archive<Box<dyn Read>>>` - The tar archive inside the star
+pub fn open(file_path: &str) -> Result<Archive<Box<dyn Read>>> {
+    let mut file = File::open(file_path)
+        .map_err(|err| Error::io(format!("Error while opening package file {}", file_path), err))?;
+
+    // peek at the magic bytes, then start over from the beginning
+    let mut header = Vec::new();
+
+    (&mut file).take(XZ_MAGIC.len() as u64).read_to_end(&mut header)
+        .and_then(|_| file.rewind())
+        .map_err(|err| Error::io(format!("Error while reading package file {}", file_path), err))?;
+
+    let reader: Box<dyn Read> = match Compression::detect(&header) {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new(file)),
+        Compression::Zstd => {
+            let decoder = zstd::Decoder::new(file)
+                .map_err(|err| Error::io(format!("Error while reading package file {}", file_path), err))?;
+
+            Box::new(decoder)
+        }
+    };
+
+    Ok(Archive::new(reader))
+}
+
+/// A writer that compresses everything written to it, used to build stars
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Wraps a writer in the given compression
+    ///
+    /// # Arguments
+    /// * `writer` - Where the compressed star is written
+    /// * `compression` - The compression to use
+    /// * `level` - The compression level, see `Compression::levels`
+    pub fn new(writer: W, compression: Compression, level: u32) -> Result<Encoder<W>> {
+        if !compression.levels().contains(&level) {
+            return Err(Error::InvalidArgument(format!("Compression level {} is out of range for {} ({:?})", level, compression, compression.levels())));
+        }
+
+        let encoder = match compression {
+            Compression::None => Encoder::None(writer),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::new(level))),
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(writer, level)),
+            Compression::Zstd => {
+                let encoder = zstd::Encoder::new(writer, level as i32)
+                    .map_err(|err| Error::io("Error while setting up zstd compression", err))?;
+
+                Encoder::Zstd(encoder)
+            }
+        };
+
+        Ok(encoder)
+    }
+
+    /// Writes out whatever is still buffered and returns the inner writer
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Encoder::None(writer) => Ok(writer),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::None(writer) => writer.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(writer) => writer.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a star with a single file in it and writes it to a temp dir
+    fn build(dir: &tempfile::TempDir, compression: Compression, level: u32) -> String {
+        let mut builder = tar::Builder::new(Encoder::new(Vec::new(), compression, level).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "package/hello", &b"hello"[..]).unwrap();
+
+        let star = builder.into_inner().unwrap().finish().unwrap();
+        assert_eq!(Compression::detect(&star), compression);
+
+        let file_path = dir.path().join(format!("{}.star", compression)).to_string_lossy().to_string();
+        std::fs::write(&file_path, star).unwrap();
+        file_path
+    }
+
+    /// The paths and contents of every entry in a star
+    fn entries(file_path: &str) -> Result<Vec<(String, String)>> {
+        let mut archive = open(file_path)?;
+        let mut entries = Vec::new();
+
+        for entry in archive.entries().map_err(|err| Error::io("Error while reading star", err))? {
+            let mut entry = entry.map_err(|err| Error::io("Error while reading star", err))?;
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|err| Error::io("Error while reading star", err))?;
+
+            entries.push((entry.path().unwrap().to_string_lossy().to_string(), contents));
+        }
+
+        Ok(entries)
+    }
+
+    #[test]
+    fn every_compression_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            for level in [*compression.levels().start(), compression.default_level(), *compression.levels().end()] {
+                let file_path = build(&dir, compression, level);
+
+                assert_eq!(entries(&file_path).unwrap(), vec![("package/hello".to_string(), "hello".to_string())], "{} at level {}", compression, level);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_magic_is_read_as_a_plain_tar_and_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("bzip2.star").to_string_lossy().to_string();
+
+        // bzip2, which stars can't be compressed with
+        let mut star = b"BZh91AY&SY".to_vec();
+        star.resize(1024, 0xaa);
+        std::fs::write(&file_path, &star).unwrap();
+
+        assert_eq!(Compression::detect(&star), Compression::None);
+        assert!(entries(&file_path).is_err());
+    }
+
+    #[test]
+    fn truncated_stars_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let file_path = build(&dir, compression, compression.default_level());
+            let star = std::fs::read(&file_path).unwrap();
+            std::fs::write(&file_path, &star[..star.len() / 2]).unwrap();
+
+            assert!(entries(&file_path).is_err(), "truncated {} star was read", compression);
+        }
+    }
+
+    #[test]
+    fn names_and_levels_are_checked() {
+        assert_eq!("ZST".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert_eq!("gz".parse::<Compression>().unwrap(), Compression::Gzip);
+        assert!(matches!("bzip2".parse::<Compression>(), Err(Error::InvalidArgument(_))));
+
+        assert!(matches!(Encoder::new(Vec::new(), Compression::Zstd, 0), Err(Error::InvalidArgument(_))));
+        assert!(matches!(Encoder::new(Vec::new(), Compression::Gzip, 10), Err(Error::InvalidArgument(_))));
+        assert!(matches!(Encoder::new(Vec::new(), Compression::None, 1), Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,529 @@
+use std::path::{Path, PathBuf};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::repo::Repository;
+
+/// Something that was done during a transaction, and how to undo it
+enum Undo {
+    /// A path was (re)created; the old file, if there was one, was moved to `backup`
+    Replaced {
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    /// A file was removed by moving it to `backup`
+    Removed {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    /// A directory that didn't exist before was created
+    CreatedDir(PathBuf),
+    /// An empty directory was removed
+    RemovedDir {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// An install or remove script ran, and this script undoes it
+    Script {
+        script: String,
+        working_dir: String,
+    },
+}
+
+/// A set of changes to the system that is either applied completely or not at all
+///
+/// Every file change goes through the transaction so it can be undone. Files that get replaced or
+/// removed are moved next to the original instead of being deleted, which keeps renames on the
+/// same filesystem. Since those backups keep directories from being empty, directories are only
+/// removed when the transaction is committed (see `remove_dir_if_empty`). The installed database
+/// is only written once everything else has succeeded.
+pub struct Transaction {
+    config: Config,
+    journal: Vec<Undo>,
+    /// Directories to remove on commit if nothing but backups is left in them
+    empty_dirs: Vec<PathBuf>,
+    staging_dir: String,
+    repo_file: String,
+    /// The installed database as it will be once the transaction is committed
+    pub installed: Repository,
+}
+
+impl Transaction {
+    /// Starts a transaction, loading the installed database and creating the staging directory
+    pub fn begin(config: &Config) -> Result<Transaction> {
+        let repo_file = format!("{}/repo.yml", config.storage_path());
+        let installed = Repository::from_file(repo_file.clone())?;
+
+        let staging_dir = format!("{}/comet-transaction-{}", config.tmp_path(), std::process::id());
+
+        if Path::new(&staging_dir).exists() {
+            std::fs::remove_dir_all(&staging_dir)
+                .map_err(|err| Error::io(format!("Error while clearing {}", staging_dir), err))?;
+        }
+
+        std::fs::create_dir_all(&staging_dir)
+            .map_err(|err| Error::io(format!("Error while creating {}", staging_dir), err))?;
+
+        Ok(Transaction {
+            config: config.clone(),
+            journal: Vec::new(),
+            empty_dirs: Vec::new(),
+            staging_dir,
+            repo_file,
+            installed,
+        })
+    }
+
+    /// The directory stars are extracted to before they are installed
+    pub fn staging_dir(&self) -> &str {
+        &self.staging_dir
+    }
+
+    /// Gets a path ready to be created, moving whatever file is there out of the way
+    ///
+    /// On rollback the new path is removed and the old file put back
+    pub fn replace(&mut self, path: &Path) -> Result<()> {
+        let backup = match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(Error::io(format!("Cannot replace directory {} with a file", path.display()), std::io::ErrorKind::IsADirectory.into()));
+            }
+            Ok(_) => Some(self.set_aside(path)?),
+            Err(_) => None,
+        };
+
+        self.journal.push(Undo::Replaced { path: path.to_path_buf(), backup });
+
+        Ok(())
+    }
+
+    /// Removes a file or symlink, keeping it around until the transaction is committed
+    pub fn remove(&mut self, path: &Path) -> Result<()> {
+        let backup = self.set_aside(path)?;
+
+        self.journal.push(Undo::Removed { path: path.to_path_buf(), backup });
+
+        Ok(())
+    }
+
+    /// Creates a directory (and its parents) if it doesn't exist yet
+    pub fn create_dir(&mut self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir(parent)?;
+        }
+
+        std::fs::create_dir(path)
+            .map_err(|err| Error::io(format!("Error while creating directory {}", path.display()), err))?;
+
+        self.journal.push(Undo::CreatedDir(path.to_path_buf()));
+
+        Ok(())
+    }
+
+    /// Removes a directory when the transaction is committed, if it is empty by then
+    ///
+    /// Files the transaction removed don't count, so a directory whose files were all removed goes
+    /// too. Directories are handled deepest first, so a parent can go once its children are gone.
+    pub fn remove_dir_if_empty(&mut self, path: &Path) {
+        self.empty_dirs.push(path.to_path_buf());
+    }
+
+    /// Removes an empty directory
+    fn remove_dir(&mut self, path: &Path) -> Result<()> {
+        let mode = std::fs::metadata(path).map(|metadata| crate::manifest::file_mode(&metadata)).unwrap_or(0o755);
+
+        std::fs::remove_dir(path)
+            .map_err(|err| Error::io(format!("Error while removing directory {}", path.display()), err))?;
+
+        self.journal.push(Undo::RemovedDir { path: path.to_path_buf(), mode });
+
+        Ok(())
+    }
+
+    /// Writes a file, replacing whatever was there
+    pub fn write_file(&mut self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.replace(path)?;
+
+        std::fs::write(path, contents)
+            .map_err(|err| Error::io(format!("Error while writing {}", path.display()), err))
+    }
+
+    /// Copies a file, replacing whatever was at the destination
+    pub fn copy_file(&mut self, from: &Path, to: &Path) -> Result<()> {
+        self.replace(to)?;
+
+        std::fs::copy(from, to)
+            .map_err(|err| Error::io(format!("Error while copying {} to {}", from.display(), to.display()), err))?;
+
+        Ok(())
+    }
+
+    /// Records that a script ran, along with the script that undoes it
+    ///
+    /// The undo script only runs if it still exists by then, after every later change was undone.
+    pub fn ran_script(&mut self, undo_script: String, working_dir: String) {
+        self.journal.push(Undo::Script { script: undo_script, working_dir });
+    }
+
+    /// Moves a file to a backup name in the same directory
+    fn set_aside(&mut self, path: &Path) -> Result<PathBuf> {
+        let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let backup = path.with_file_name(format!(".{}.comet-{}-{}", file_name, std::process::id(), self.journal.len()));
+
+        std::fs::rename(path, &backup)
+            .map_err(|err| Error::io(format!("Error while moving {} aside", path.display()), err))?;
+
+        Ok(backup)
+    }
+
+    /// Whether a path is the backup of a file this transaction replaced or removed
+    fn is_backup(&self, path: &Path) -> bool {
+        self.journal.iter().any(|undo| match undo {
+            Undo::Replaced { backup: Some(backup), .. } | Undo::Removed { backup, .. } => backup == path,
+            _ => false,
+        })
+    }
+
+    /// Records that a backup was moved, so rollback restores it from its new place
+    fn moved_backup(&mut self, from: &Path, to: &Path) {
+        for undo in self.journal.iter_mut() {
+            match undo {
+                Undo::Replaced { backup: Some(backup), .. } | Undo::Removed { backup, .. } if backup == from => {
+                    *backup = to.to_path_buf();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Removes the directories queued by `remove_dir_if_empty` that only hold backups anymore
+    ///
+    /// The backups are moved up into the parent directory first. Every step is journaled, so a
+    /// rollback brings back the directories and the files in them.
+    fn remove_empty_dirs(&mut self) -> Result<()> {
+        let mut dirs = std::mem::take(&mut self.empty_dirs);
+
+        // deepest first, so a parent is only looked at once its children are gone
+        dirs.sort_by(|a, b| b.components().count().cmp(&a.components().count()).then(a.cmp(b)));
+        dirs.dedup();
+
+        for dir in dirs {
+            let (Ok(entries), Some(parent)) = (std::fs::read_dir(&dir), dir.parent()) else {
+                continue;
+            };
+
+            let entries: Vec<PathBuf> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+
+            if !entries.iter().all(|entry| self.is_backup(entry)) {
+                continue;
+            }
+
+            let mut moved = Vec::new();
+
+            for backup in entries {
+                let to = parent.join(backup.file_name().unwrap_or_default());
+
+                // a directory on another filesystem can't give up its backups, it stays then
+                if std::fs::rename(&backup, &to).is_err() {
+                    break;
+                }
+
+                self.moved_backup(&backup, &to);
+                moved.push((backup, to));
+            }
+
+            if std::fs::read_dir(&dir).map(|mut entries| entries.next().is_none()).unwrap_or(false) {
+                self.remove_dir(&dir)?;
+            } else {
+                for (backup, to) in moved {
+                    std::fs::rename(&to, &backup)
+                        .map_err(|err| Error::io(format!("Error while moving {} back", to.display()), err))?;
+                    self.moved_backup(&to, &backup);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the installed database and throws away everything kept for rollback
+    ///
+    /// Directories queued by `remove_dir_if_empty` are removed first. If that or writing the
+    /// database fails the transaction is rolled back instead.
+    pub fn commit(mut self) -> Result<()> {
+        if let Err(err) = self.remove_empty_dirs() {
+            return self.rollback(err);
+        }
+
+        if let Err(err) = self.installed.to_file(self.repo_file.clone()) {
+            return self.rollback(err);
+        }
+
+        // nothing can fail anymore, so the backups aren't needed
+        for undo in self.journal.drain(..) {
+            match undo {
+                Undo::Replaced { backup: Some(backup), .. } | Undo::Removed { backup, .. } => {
+                    let _ = std::fs::remove_file(backup);
+                }
+                _ => {}
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&self.staging_dir);
+
+        Ok(())
+    }
+
+    /// Undoes everything the transaction did, newest first
+    ///
+    /// # Arguments
+    /// * `err` - The error that caused the rollback
+    ///
+    /// # Returns
+    /// * `Result<()>` - Always an error: `err` itself, or `Error::Rollback` if undoing failed too
+    pub fn rollback(mut self, err: Error) -> Result<()> {
+        let mut failures = Vec::new();
+
+        while let Some(undo) = self.journal.pop() {
+            if let Err(failure) = self.undo(undo) {
+                failures.push(failure.to_string());
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&self.staging_dir);
+
+        if failures.is_empty() {
+            Err(err)
+        } else {
+            Err(Error::Rollback { source: Box::new(err), failures })
+        }
+    }
+
+    fn undo(&self, undo: Undo) -> Result<()> {
+        match undo {
+            Undo::Replaced { path, backup } => {
+                if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                    if !metadata.is_dir() {
+                        std::fs::remove_file(&path)
+                            .map_err(|err| Error::io(format!("Error while removing {}", path.display()), err))?;
+                    }
+                }
+
+                if let Some(backup) = backup {
+                    std::fs::rename(&backup, &path)
+                        .map_err(|err| Error::io(format!("Error while restoring {}", path.display()), err))?;
+                }
+            }
+            Undo::Removed { path, backup } => {
+                std::fs::rename(&backup, &path)
+                    .map_err(|err| Error::io(format!("Error while restoring {}", path.display()), err))?;
+            }
+            Undo::CreatedDir(path) => {
+                // something else may have been put in there since, in which case it stays
+                let _ = std::fs::remove_dir(path);
+            }
+            Undo::RemovedDir { path, mode } => {
+                std::fs::create_dir(&path)
+                    .map_err(|err| Error::io(format!("Error while restoring directory {}", path.display()), err))?;
+                crate::manifest::set_mode(mode, &path)?;
+            }
+            Undo::Script { script, working_dir } => {
+                if Path::new(&script).exists() {
+                    crate::run_script(&self.config, script, working_dir)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A config for an install root in a temp dir, with an empty installed database
+    fn config(root: &Path) -> Config {
+        let mut config = Config::new(Vec::new(), false, "/var/lib/comet".to_string(), "/tmp".to_string());
+        config.root = root.to_string_lossy().to_string();
+
+        std::fs::create_dir_all(config.storage_path()).unwrap();
+        std::fs::write(format!("{}/repo.yml", config.storage_path()), "").unwrap();
+
+        config
+    }
+
+    /// Everything under a directory except comet's own files, as sorted relative paths
+    fn tree(root: &Path) -> Vec<String> {
+        fn walk(root: &Path, dir: &Path, paths: &mut Vec<String>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                paths.push(path.strip_prefix(root).unwrap().to_string_lossy().to_string());
+
+                if path.is_dir() {
+                    walk(root, &path, paths);
+                }
+            }
+        }
+
+        let mut paths = Vec::new();
+        walk(root, root, &mut paths);
+        paths.retain(|path| !path.starts_with("var") && !path.starts_with("tmp"));
+        paths.sort();
+        paths
+    }
+
+    fn failed() -> Error {
+        Error::InvalidArgument("failed".to_string())
+    }
+
+    #[test]
+    fn commit_keeps_the_changes_and_drops_the_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        std::fs::write(dir.path().join("replaced"), "old").unwrap();
+        std::fs::write(dir.path().join("removed"), "old").unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let staging_dir = transaction.staging_dir().to_string();
+
+        transaction.write_file(&dir.path().join("replaced"), b"new").unwrap();
+        transaction.remove(&dir.path().join("removed")).unwrap();
+        transaction.create_dir(&dir.path().join("a/b")).unwrap();
+        transaction.write_file(&dir.path().join("a/b/created"), b"new").unwrap();
+        transaction.installed.add_package(crate::package::Package::new("hello".to_string(), String::new(), "1.0.0".to_string(), Default::default(), "MIT".to_string(), Vec::new()));
+        transaction.commit().unwrap();
+
+        assert_eq!(tree(dir.path()), vec!["a", "a/b", "a/b/created", "replaced"]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("replaced")).unwrap(), "new");
+        assert!(!Path::new(&staging_dir).exists());
+
+        let installed = Repository::from_file(format!("{}/repo.yml", config.storage_path())).unwrap();
+        assert!(installed.get_package("hello".to_string()).is_some());
+    }
+
+    #[test]
+    fn rollback_puts_everything_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        std::fs::write(dir.path().join("replaced"), "old").unwrap();
+        std::fs::write(dir.path().join("removed"), "old").unwrap();
+        std::os::unix::fs::symlink("replaced", dir.path().join("link")).unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+
+        transaction.write_file(&dir.path().join("replaced"), b"new").unwrap();
+        transaction.remove(&dir.path().join("removed")).unwrap();
+        transaction.remove(&dir.path().join("link")).unwrap();
+        transaction.create_dir(&dir.path().join("a/b")).unwrap();
+        transaction.write_file(&dir.path().join("a/b/created"), b"new").unwrap();
+        transaction.installed.add_package(crate::package::Package::new("hello".to_string(), String::new(), "1.0.0".to_string(), Default::default(), "MIT".to_string(), Vec::new()));
+
+        assert!(matches!(transaction.rollback(failed()), Err(Error::InvalidArgument(_))));
+
+        assert_eq!(tree(dir.path()), vec!["link", "removed", "replaced"]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("replaced")).unwrap(), "old");
+        assert_eq!(std::fs::read_link(dir.path().join("link")).unwrap(), Path::new("replaced"));
+        assert_eq!(std::fs::read_to_string(format!("{}/repo.yml", config.storage_path())).unwrap(), "");
+    }
+
+    #[test]
+    fn rollback_runs_undo_scripts_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let log = dir.path().join("log");
+
+        for name in ["first", "second"] {
+            std::fs::write(dir.path().join(name), format!("echo {} >> \"$COMET_ROOT/log\"\n", name)).unwrap();
+        }
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        let working_dir = dir.path().to_string_lossy().to_string();
+
+        transaction.ran_script(dir.path().join("first").to_string_lossy().to_string(), working_dir.clone());
+        transaction.ran_script(dir.path().join("second").to_string_lossy().to_string(), working_dir.clone());
+
+        // an undo script that is gone by then is skipped
+        transaction.ran_script(dir.path().join("missing").to_string_lossy().to_string(), working_dir);
+
+        assert!(transaction.rollback(failed()).is_err());
+        assert_eq!(std::fs::read_to_string(log).unwrap(), "second\nfirst\n");
+    }
+
+    #[test]
+    fn failed_undo_is_reported_with_the_original_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        std::fs::write(dir.path().join("undo"), "exit 1\n").unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+        transaction.ran_script(dir.path().join("undo").to_string_lossy().to_string(), dir.path().to_string_lossy().to_string());
+
+        let Err(Error::Rollback { source, failures }) = transaction.rollback(failed()) else {
+            panic!("the failed undo script wasn't reported");
+        };
+
+        assert!(matches!(*source, Error::InvalidArgument(_)));
+        assert_eq!(failures.len(), 1);
+    }
+
+    #[test]
+    fn emptied_directories_are_removed_on_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        std::fs::create_dir_all(dir.path().join("kept")).unwrap();
+        std::fs::write(dir.path().join("a/b/file"), "").unwrap();
+        std::fs::write(dir.path().join("kept/file"), "").unwrap();
+        std::fs::write(dir.path().join("kept/other"), "").unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+
+        transaction.remove(&dir.path().join("a/b/file")).unwrap();
+        transaction.remove(&dir.path().join("kept/file")).unwrap();
+
+        // queued parent first, they are still removed deepest first
+        transaction.remove_dir_if_empty(&dir.path().join("a"));
+        transaction.remove_dir_if_empty(&dir.path().join("a/b"));
+        transaction.remove_dir_if_empty(&dir.path().join("kept"));
+        transaction.remove_dir_if_empty(&dir.path().join("missing"));
+
+        // nothing is removed before the commit, so a rollback has less to undo
+        assert!(dir.path().join("a/b").is_dir());
+
+        transaction.commit().unwrap();
+
+        assert_eq!(tree(dir.path()), vec!["kept", "kept/other"]);
+    }
+
+    #[test]
+    fn failed_commit_brings_removed_directories_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        std::fs::write(dir.path().join("a/b/file"), "contents").unwrap();
+
+        let mut transaction = Transaction::begin(&config).unwrap();
+
+        transaction.remove(&dir.path().join("a/b/file")).unwrap();
+        transaction.remove_dir_if_empty(&dir.path().join("a/b"));
+        transaction.remove_dir_if_empty(&dir.path().join("a"));
+
+        // the installed database can't be written, so the commit fails after the directories went
+        let repo_file = format!("{}/repo.yml", config.storage_path());
+        std::fs::remove_file(&repo_file).unwrap();
+        std::fs::create_dir(&repo_file).unwrap();
+
+        assert!(transaction.commit().is_err());
+
+        assert_eq!(tree(dir.path()), vec!["a", "a/b", "a/b/file"]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("a/b/file")).unwrap(), "contents");
+    }
+}
@@ -0,0 +1,745 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+use reqwest::{Certificate, Identity, NoProxy, Proxy, StatusCode};
+use reqwest::blocking::Client;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE};
+use sha2::{Digest, Sha256};
+use crate::config::{Config, HttpConfig};
+use crate::error::{Error, Result};
+
+/// The client every HTTP request goes through, with the settings and install root it was built for
+static CLIENT: Mutex<Option<(HttpConfig, String, Client)>> = Mutex::new(None);
+
+/// How long to wait before the first retry, doubled for every retry after it
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest to wait between two retries, however many retries there were before
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where a galaxy URL points
+enum Location {
+    /// Served over HTTP(S)
+    Web(String),
+    /// A directory on this machine, like a mounted DVD or NFS share
+    File(PathBuf),
+}
+
+/// Works out where a URL points
+///
+/// `file://` URLs and bare absolute paths are read from the filesystem, everything else over HTTP.
+/// Filesystem paths are not resolved under the install root, since the galaxy lives on the
+/// machine comet runs on.
+fn locate(url: &str) -> Location {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Location::File(PathBuf::from(path));
+    }
+
+    let path = PathBuf::from(url);
+
+    if path.is_absolute() {
+        Location::File(path)
+    } else {
+        Location::Web(url.to_string())
+    }
+}
+
+/// Sets up the shared HTTP client for a config, unless it is already set up for the same settings
+///
+/// # Arguments
+/// * `config` - The config holding the HTTP settings
+pub fn configure(config: &Config) -> Result<()> {
+    let mut current = CLIENT.lock().unwrap_or_else(|err| err.into_inner());
+
+    if let Some((http, root, _)) = current.as_ref() {
+        if http == &config.http && root == &config.root {
+            return Ok(());
+        }
+    }
+
+    *current = Some((config.http.clone(), config.root.clone(), build_client(config)?));
+
+    Ok(())
+}
+
+/// The shared HTTP client, set up with the default settings if `configure` hasn't been called
+fn client() -> Result<Client> {
+    let mut current = CLIENT.lock().unwrap_or_else(|err| err.into_inner());
+
+    if let Some((_, _, client)) = current.as_ref() {
+        return Ok(client.clone());
+    }
+
+    let config = Config::new(Vec::new(), false, String::new(), String::new());
+    let client = build_client(&config)?;
+    *current = Some((config.http, config.root, client.clone()));
+
+    Ok(client)
+}
+
+/// Builds an HTTP client from the HTTP settings of a config
+fn build_client(config: &Config) -> Result<Client> {
+    let http = &config.http;
+    let invalid = |message: String, source: Error| Error::Config { message, source: Some(Box::new(source)) };
+    let seconds = |seconds: u64| if seconds == 0 { None } else { Some(Duration::from_secs(seconds)) };
+
+    let mut builder = Client::builder()
+        .user_agent(http.user_agent.clone())
+        .connect_timeout(seconds(http.connect_timeout))
+        .timeout(seconds(http.read_timeout));
+
+    let no_proxy = NoProxy::from_string(&http.no_proxy.join(","));
+
+    if let Some(url) = &http.http_proxy {
+        let proxy = Proxy::http(url).map_err(|err| invalid(format!("Invalid http_proxy {}", url), Error::parse(url.clone(), err)))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+    }
+
+    if let Some(url) = &http.https_proxy {
+        let proxy = Proxy::https(url).map_err(|err| invalid(format!("Invalid https_proxy {}", url), Error::parse(url.clone(), err)))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+    }
+
+    for path in &http.ca_certificates {
+        let path = config.resolve(path);
+        let pem = std::fs::read(&path).map_err(|err| invalid(format!("Error while reading CA certificates {}", path), Error::io(path.clone(), err)))?;
+
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .map_err(|err| invalid(format!("Invalid CA certificates {}", path), Error::parse(path.clone(), err)))?;
+
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    match (&http.client_certificate, &http.client_key) {
+        (Some(certificate), Some(key)) => {
+            let certificate = config.resolve(certificate);
+            let key = config.resolve(key);
+
+            let certificate_pem = std::fs::read(&certificate)
+                .map_err(|err| invalid(format!("Error while reading client certificate {}", certificate), Error::io(certificate.clone(), err)))?;
+            let key_pem = std::fs::read(&key)
+                .map_err(|err| invalid(format!("Error while reading client key {}", key), Error::io(key.clone(), err)))?;
+
+            let identity = Identity::from_pkcs8_pem(&certificate_pem, &key_pem)
+                .map_err(|err| invalid(format!("Invalid client certificate {} or key {}", certificate, key), Error::parse(certificate.clone(), err)))?;
+
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err(Error::config("client_certificate and client_key have to be set together")),
+    }
+
+    builder.build().map_err(|err| invalid("Error while setting up the HTTP client".to_string(), Error::parse("http", err)))
+}
+
+/// Joins a path onto a galaxy URL
+///
+/// # Arguments
+/// * `galaxy` - The URL or path of the galaxy
+/// * `path` - The path of a file in the galaxy
+pub fn join(galaxy: &str, path: &str) -> String {
+    format!("{}/{}", galaxy.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+/// Reads a whole file from a galaxy into memory
+///
+/// # Arguments
+/// * `url` - The URL or path of the file
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    match locate(url) {
+        Location::Web(url) => {
+            let response = client()?.get(&url).send()
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| Error::network(url.clone(), err))?;
+
+            let bytes = response.bytes().map_err(|err| Error::network(url.clone(), err))?;
+
+            Ok(bytes.to_vec())
+        }
+        Location::File(path) => std::fs::read(&path)
+            .map_err(|err| Error::io(format!("Error while reading {}", path.display()), err)),
+    }
+}
+
+/// A file read from a galaxy, with what the server said to ask it whether the file changed
+pub struct Fetched {
+    pub data: Vec<u8>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Reads a whole file from a galaxy, unless the server says it hasn't changed
+///
+/// The ETag and Last-Modified values from an earlier fetch from the same URL are sent along, so
+/// the server can answer with 304 Not Modified instead of the file. Files on disk are always read.
+///
+/// # Arguments
+/// * `url` - The URL or path of the file
+/// * `etag` - The ETag the server sent last time
+/// * `last_modified` - The Last-Modified date the server sent last time
+///
+/// # Returns
+/// * `Result<Option<Fetched>>` - The file, or `None` if it hasn't changed
+pub fn fetch_if_changed(url: &str, etag: Option<&str>, last_modified: Option<&str>) -> Result<Option<Fetched>> {
+    let url = match locate(url) {
+        Location::Web(url) => url,
+        Location::File(_) => return fetch(url).map(|data| Some(Fetched { data, etag: None, last_modified: None })),
+    };
+
+    let mut request = client()?.get(&url);
+
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+
+    if let Some(last_modified) = last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let response = request.send().map_err(|err| Error::network(url.clone(), err))?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+
+    let response = response.error_for_status().map_err(|err| Error::network(url.clone(), err))?;
+
+    let header = |name| response.headers().get(name).and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok()).map(|value| value.to_string());
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+
+    let data = response.bytes().map_err(|err| Error::network(url.clone(), err))?.to_vec();
+
+    Ok(Some(Fetched { data, etag, last_modified }))
+}
+
+/// Copies a file from a galaxy to a local file, hashing it on the way
+///
+/// The file is written to `<file_path>.part` first and renamed once it is complete. If a `.part`
+/// file is left over from an earlier attempt, only the rest of the file is fetched, with an HTTP
+/// Range request for galaxies served over HTTP.
+///
+/// What identifies the version of the file being fetched, its ETag or Last-Modified date, or the
+/// size and modification time of a file on disk, is kept in `<file_path>.part.validator`. A `.part`
+/// file is only resumed if the file is still the same, otherwise it is fetched from the start.
+///
+/// # Arguments
+/// * `url` - The URL or path of the file
+/// * `file_path` - Where to save it
+/// * `progress` - Called with the bytes written so far and the size of the file, if it is known
+///
+/// # Returns
+/// * `Result<String>` - The SHA-256 of the file
+pub fn download(url: &str, file_path: &str, progress: &mut dyn FnMut(u64, Option<u64>)) -> Result<String> {
+    let part_path = format!("{}.part", file_path);
+    let validator_path = validator_path(&part_path);
+    let mut hasher = Sha256::new();
+
+    // hash what an earlier attempt already fetched, so it doesn't have to be fetched again
+    let resume_from = match File::open(&part_path) {
+        Ok(mut part) => std::io::copy(&mut part, &mut hasher)
+            .map_err(|err| Error::io(format!("Error while reading {}", part_path), err))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => 0,
+        Err(err) => return Err(Error::io(format!("Error while opening {}", part_path), err)),
+    };
+
+    // without a validator there's no telling which version of the file the part file is from
+    let validator = match resume_from {
+        0 => None,
+        _ => std::fs::read_to_string(&validator_path).ok(),
+    };
+
+    match locate(url) {
+        Location::Web(url) => {
+            let mut request = client()?.get(&url);
+
+            // the server only sends the rest if the file is still the one the part file is from
+            if let Some(validator) = &validator {
+                request = request
+                    .header(RANGE, format!("bytes={}-", resume_from))
+                    .header(IF_RANGE, validator.as_str());
+            }
+
+            let response = request.send().map_err(|err| Error::network(url.clone(), err))?;
+
+            // the part file is as long as the file or longer, so it isn't the start of this file
+            if validator.is_some() && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                remove_part(&part_path)?;
+                return download(&url, file_path, progress);
+            }
+
+            let mut response = response.error_for_status().map_err(|err| Error::network(url.clone(), err))?;
+
+            // a server that ignores the range, or has a newer file, sends the whole file again
+            let resumed = validator.is_some() && response.status() == StatusCode::PARTIAL_CONTENT;
+            let done = if resumed { resume_from } else { 0 };
+
+            if !resumed {
+                hasher = Sha256::new();
+            }
+
+            // weak ETags can't be used for ranges, the Last-Modified date can
+            let header = |name| response.headers().get(name).and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok()).map(|value| value.to_string());
+            let current = header(ETAG).filter(|etag| !etag.starts_with("W/")).or_else(|| header(LAST_MODIFIED));
+            write_validator(&validator_path, current.as_deref())?;
+
+            let total = response.content_length().map(|length| length + done);
+            let mut sink = Sink::open(&part_path, resumed, hasher, done, total, progress)?;
+
+            response.copy_to(&mut sink).map_err(|err| Error::network(url.clone(), err))?;
+            hasher = sink.hasher;
+        }
+        Location::File(path) => {
+            let mut file = File::open(&path)
+                .map_err(|err| Error::io(format!("Error while opening {}", path.display()), err))?;
+
+            let metadata = file.metadata()
+                .map_err(|err| Error::io(format!("Error while reading {}", path.display()), err))?;
+            let length = metadata.len();
+
+            let modified = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_nanos())
+                .unwrap_or(0);
+            let current = format!("{} {}", length, modified);
+
+            let resumed = resume_from > 0 && resume_from <= length && validator.as_deref() == Some(current.as_str());
+            let done = if resumed { resume_from } else { 0 };
+
+            if resumed {
+                file.seek(SeekFrom::Start(done))
+                    .map_err(|err| Error::io(format!("Error while reading {}", path.display()), err))?;
+            } else {
+                hasher = Sha256::new();
+            }
+
+            write_validator(&validator_path, Some(&current))?;
+
+            let mut sink = Sink::open(&part_path, resumed, hasher, done, Some(length), progress)?;
+
+            std::io::copy(&mut file, &mut sink)
+                .map_err(|err| Error::io(format!("Error while copying {} to {}", path.display(), part_path), err))?;
+            hasher = sink.hasher;
+        }
+    }
+
+    std::fs::rename(&part_path, file_path)
+        .map_err(|err| Error::io(format!("Error while renaming {} to {}", part_path, file_path), err))?;
+
+    write_validator(&validator_path, None)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Where the validator of a part file is kept
+pub(crate) fn validator_path(part_path: &str) -> String {
+    format!("{}.validator", part_path)
+}
+
+/// Replaces the validator of a part file, or removes it if there is none
+fn write_validator(validator_path: &str, validator: Option<&str>) -> Result<()> {
+    let result = match validator {
+        Some(validator) => std::fs::write(validator_path, validator),
+        None => match std::fs::remove_file(validator_path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        },
+    };
+
+    result.map_err(|err| Error::io(format!("Error while writing {}", validator_path), err))
+}
+
+/// Removes a part file that can't be resumed
+fn remove_part(part_path: &str) -> Result<()> {
+    std::fs::remove_file(part_path).map_err(|err| Error::io(format!("Error while removing {}", part_path), err))?;
+
+    write_validator(&validator_path(part_path), None)
+}
+
+/// Writes a download to its part file, hashing it and reporting progress as it goes
+struct Sink<'a> {
+    file: File,
+    hasher: Sha256,
+    done: u64,
+    total: Option<u64>,
+    progress: &'a mut dyn FnMut(u64, Option<u64>),
+}
+
+impl<'a> Sink<'a> {
+    /// Opens the part file, appending to it when resuming and starting it over otherwise
+    fn open(part_path: &str, resume: bool, hasher: Sha256, done: u64, total: Option<u64>, progress: &'a mut dyn FnMut(u64, Option<u64>)) -> Result<Sink<'a>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(part_path)
+            .map_err(|err| Error::io(format!("Error while creating {}", part_path), err))?;
+
+        progress(done, total);
+
+        Ok(Sink { file, hasher, done, total, progress })
+    }
+}
+
+impl Write for Sink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+
+        self.hasher.update(&buf[..written]);
+        self.done += written as u64;
+        (self.progress)(self.done, self.total);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Runs a fetch against each mirror of a galaxy in turn until one succeeds
+///
+/// Connection errors and server errors are retried on the same mirror, waiting twice as long
+/// each time, up to `MAX_RETRY_BACKOFF`. Anything else, like a missing file, a checksum mismatch or a bad signature, moves
+/// straight on to the next mirror.
+///
+/// # Arguments
+/// * `resource` - What is being fetched, used in errors
+/// * `mirrors` - The mirrors to try, in order
+/// * `retries` - How many times to retry a mirror before moving on
+/// * `attempt` - Fetches the resource from one mirror
+///
+/// # Returns
+/// * `Result<(T, String)>` - The result and the mirror that served it
+pub fn with_mirrors<T>(resource: &str, mirrors: &[String], retries: u32, mut attempt: impl FnMut(&str) -> Result<T>) -> Result<(T, String)> {
+    let mut failures = Vec::new();
+
+    for mirror in mirrors {
+        let mut tries = 0;
+
+        loop {
+            match attempt(mirror) {
+                Ok(value) => return Ok((value, mirror.clone())),
+                Err(err) if tries < retries && is_transient(&err) => {
+                    std::thread::sleep(retry_backoff(tries));
+                    tries += 1;
+                }
+                Err(err) => {
+                    failures.push((mirror.clone(), err));
+                    break;
+                }
+            }
+        }
+    }
+
+    Err(Error::Unavailable { resource: resource.to_string(), failures })
+}
+
+/// How long to wait before retrying, after `tries` retries already
+fn retry_backoff(tries: u32) -> Duration {
+    let factor = 2u32.checked_pow(tries).unwrap_or(u32::MAX);
+
+    RETRY_BACKOFF.saturating_mul(factor).min(MAX_RETRY_BACKOFF)
+}
+
+/// Whether an error might go away if the same request is made again
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::Network { source: Some(source), .. } => match source.status() {
+            Some(status) => status.is_server_error() || status.as_u16() == 429,
+            None => source.is_connect() || source.is_timeout() || source.is_request() || source.is_body(),
+        },
+        Error::Network { source: None, .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    /// The head of every request a test server got, request line and headers
+    pub(crate) type Requests = Arc<Mutex<Vec<String>>>;
+
+    /// Serves HTTP on a local port, answering every request with whatever `respond` returns
+    ///
+    /// # Returns
+    /// * `(String, Requests)` - The URL of the server and the requests it got so far
+    pub(crate) fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let seen = requests.clone();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+
+                // requests from comet never have a body, so the head is all there is
+                loop {
+                    let mut line = String::new();
+
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+
+                    head.push_str(&line);
+                }
+
+                seen.lock().unwrap().push(head.clone());
+                let _ = std::io::Write::write_all(&mut stream, &respond(&head));
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// An HTTP response that closes the connection once it is sent
+    pub(crate) fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        response.push_str("\r\n");
+
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// The value of a header in a request head, matching the name case insensitively
+    pub(crate) fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    fn mirrors(mirrors: &[&str]) -> Vec<String> {
+        mirrors.iter().map(|mirror| mirror.to_string()).collect()
+    }
+
+    /// A connection error, which is worth retrying
+    fn transient() -> Error {
+        Error::Network { url: "mirror".to_string(), source: None }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(retry_backoff(0), RETRY_BACKOFF);
+        assert_eq!(retry_backoff(1), RETRY_BACKOFF * 2);
+        assert_eq!(retry_backoff(3), RETRY_BACKOFF * 8);
+        assert_eq!(retry_backoff(10), MAX_RETRY_BACKOFF);
+
+        // far past where 2^tries fits in a u32
+        assert_eq!(retry_backoff(32), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(u32::MAX), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn first_working_mirror_wins() {
+        let mut tried = Vec::new();
+
+        let result = with_mirrors("star", &mirrors(&["a", "b", "c"]), 0, |mirror| {
+            tried.push(mirror.to_string());
+
+            match mirror {
+                "a" => Err(Error::PackageNotFound("star".to_string())),
+                _ => Ok(mirror.to_uppercase()),
+            }
+        });
+
+        assert_eq!(result.unwrap(), ("B".to_string(), "b".to_string()));
+        assert_eq!(tried, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn transient_errors_are_retried_before_moving_on() {
+        let mut tried = Vec::new();
+
+        let result = with_mirrors("star", &mirrors(&["a", "b"]), 1, |mirror| {
+            tried.push(mirror.to_string());
+
+            match mirror {
+                "a" => Err(transient()),
+                _ => Ok(()),
+            }
+        });
+
+        assert_eq!(result.unwrap().1, "b");
+        assert_eq!(tried, vec!["a", "a", "b"]);
+    }
+
+    #[test]
+    fn every_failure_is_reported_in_order() {
+        let result = with_mirrors("star", &mirrors(&["a", "b"]), 0, |mirror| -> Result<()> {
+            match mirror {
+                "a" => Err(transient()),
+                _ => Err(Error::PackageNotFound("star".to_string())),
+            }
+        });
+
+        let Err(Error::Unavailable { resource, failures }) = result else {
+            panic!("expected every mirror to fail");
+        };
+
+        assert_eq!(resource, "star");
+        assert_eq!(failures.iter().map(|(mirror, _)| mirror.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(matches!(failures[0].1, Error::Network { .. }));
+        assert!(matches!(failures[1].1, Error::PackageNotFound(_)));
+    }
+
+    #[test]
+    fn server_errors_are_retried_and_missing_files_are_not() {
+        let (down, down_requests) = serve(|_| response("503 Service Unavailable", &[], b""));
+        let (missing, missing_requests) = serve(|_| response("404 Not Found", &[], b""));
+        let (up, _) = serve(|_| response("200 OK", &[], b"star"));
+
+        let (data, mirror) = with_mirrors("star", &[down, missing, up.clone()], 1, |mirror| fetch(&join(mirror, "hello.star"))).unwrap();
+
+        assert_eq!(data, b"star");
+        assert_eq!(mirror, up);
+        assert_eq!(down_requests.lock().unwrap().len(), 2);
+        assert_eq!(missing_requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn file_mirrors_fail_over_too() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("hello.star"), "star").unwrap();
+
+        let empty = tempfile::tempdir().unwrap();
+        let mirrors = vec![
+            format!("file://{}", empty.path().display()),
+            format!("file://{}", dir.path().display()),
+        ];
+
+        let (data, mirror) = with_mirrors("star", &mirrors, 0, |mirror| fetch(&join(mirror, "hello.star"))).unwrap();
+
+        assert_eq!(data, b"star");
+        assert_eq!(mirror, mirrors[1]);
+    }
+
+    /// A star served with the ETag "v2", which only sends a range when asked with that ETag
+    fn star_server() -> (String, Requests) {
+        serve(|head| {
+            let body = b"hello world";
+
+            match (header(head, "Range"), header(head, "If-Range")) {
+                (Some(range), Some("\"v2\"")) => {
+                    let from: usize = range.trim_start_matches("bytes=").trim_end_matches('-').parse().unwrap();
+                    response("206 Partial Content", &[("ETag", "\"v2\"")], &body[from..])
+                }
+                _ => response("200 OK", &[("ETag", "\"v2\"")], body),
+            }
+        })
+    }
+
+    /// Leaves a part file behind, like an interrupted download
+    fn interrupted(file_path: &str, part: &str, validator: Option<&str>) {
+        std::fs::write(format!("{}.part", file_path), part).unwrap();
+
+        if let Some(validator) = validator {
+            std::fs::write(validator_path(&format!("{}.part", file_path)), validator).unwrap();
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    #[test]
+    fn download_resumes_the_same_file() {
+        let (url, requests) = star_server();
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("hello.star").to_string_lossy().to_string();
+
+        interrupted(&file_path, "hello", Some("\"v2\""));
+
+        let digest = download(&join(&url, "hello.star"), &file_path, &mut |_, _| {}).unwrap();
+
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"hello world");
+        assert_eq!(digest, sha256(b"hello world"));
+        assert_eq!(header(&requests.lock().unwrap()[0], "Range"), Some("bytes=5-"));
+
+        // nothing is left over to resume next time
+        assert!(!Path::new(&validator_path(&format!("{}.part", file_path))).exists());
+    }
+
+    #[test]
+    fn download_starts_over_when_the_file_changed() {
+        let (url, _) = star_server();
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("hello.star").to_string_lossy().to_string();
+
+        // the part file is from an older star, which the server no longer has
+        interrupted(&file_path, "howdy", Some("\"v1\""));
+
+        let digest = download(&join(&url, "hello.star"), &file_path, &mut |_, _| {}).unwrap();
+
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"hello world");
+        assert_eq!(digest, sha256(b"hello world"));
+    }
+
+    #[test]
+    fn download_starts_over_without_a_validator() {
+        let (url, requests) = star_server();
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("hello.star").to_string_lossy().to_string();
+
+        interrupted(&file_path, "howdy", None);
+
+        download(&join(&url, "hello.star"), &file_path, &mut |_, _| {}).unwrap();
+
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"hello world");
+        assert_eq!(header(&requests.lock().unwrap()[0], "Range"), None);
+    }
+
+    #[test]
+    fn interrupted_download_remembers_what_it_is_from() {
+        // the server promises more than it sends, like a connection that drops halfway
+        let (url, _) = serve(|_| b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\nETag: \"v2\"\r\nConnection: close\r\n\r\nhello".to_vec());
+
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("hello.star").to_string_lossy().to_string();
+
+        assert!(download(&join(&url, "hello.star"), &file_path, &mut |_, _| {}).is_err());
+
+        let part_path = format!("{}.part", file_path);
+        assert_eq!(std::fs::read_to_string(&part_path).unwrap(), "hello");
+        assert_eq!(std::fs::read_to_string(validator_path(&part_path)).unwrap(), "\"v2\"");
+    }
+
+    #[test]
+    fn file_download_starts_over_when_the_file_changed() {
+        let galaxy = tempfile::tempdir().unwrap();
+        std::fs::write(galaxy.path().join("hello.star"), "hello world").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("hello.star").to_string_lossy().to_string();
+
+        interrupted(&file_path, "howdy", Some("11 0"));
+
+        let url = format!("file://{}", galaxy.path().join("hello.star").display());
+        let digest = download(&url, &file_path, &mut |_, _| {}).unwrap();
+
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"hello world");
+        assert_eq!(digest, sha256(b"hello world"));
+    }
+}
@@ -0,0 +1,102 @@
+// inc/comet.h is generated by cbindgen from the `extern "C"` functions in lib.rs, run from the
+// repository root after changing any of them:
+//
+//     cbindgen --config cbindgen.toml --crate comet --output comet/inc/comet.h
+//
+// this checks that nobody forgot to, without needing cbindgen to run the tests
+
+/// The C type cbindgen writes for a Rust type used in the C API
+fn c_type(rust: &str) -> String {
+    let rust = rust.trim();
+
+    if let Some(args) = rust.strip_prefix("Option<extern \"C\" fn(").and_then(|rest| rest.strip_suffix(")>")) {
+        let args: Vec<String> = args.split(',').map(|arg| c_type(arg).replace(" *", "*")).collect();
+        return format!("void (*{{}})({})", args.join(", "));
+    }
+
+    match rust {
+        "*const c_char" => "const char *",
+        "bool" => "bool",
+        "u64" => "uint64_t",
+        other => panic!("no C type for {} in the C API, add it here", other),
+    }.to_string()
+}
+
+/// Declares a parameter or return value the way cbindgen does, `const char *name` or `bool name`
+fn declare(c_type: &str, name: &str) -> String {
+    if c_type.contains("{}") {
+        c_type.replace("{}", name)
+    } else if c_type.ends_with('*') {
+        format!("{}{}", c_type, name)
+    } else {
+        format!("{} {}", c_type, name)
+    }
+}
+
+/// The C declarations of every `extern "C"` function in lib.rs, sorted by name like cbindgen.toml asks
+fn expected_declarations(source: &str) -> Vec<String> {
+    let mut declarations = Vec::new();
+
+    for line in source.lines() {
+        let Some(rest) = line.strip_prefix("pub extern \"C\" fn ").or_else(|| line.strip_prefix("pub unsafe extern \"C\" fn ")) else {
+            continue;
+        };
+
+        let (name, rest) = rest.split_once('(').unwrap();
+        let (args, ret) = rest.rsplit_once(')').unwrap();
+
+        // split on the commas between parameters, not the ones inside a callback type
+        let mut params = Vec::new();
+        let mut depth = 0;
+        let mut current = String::new();
+
+        for c in args.chars() {
+            match c {
+                '(' | '<' => depth += 1,
+                ')' | '>' => depth -= 1,
+                ',' if depth == 0 => {
+                    params.push(std::mem::take(&mut current));
+                    continue;
+                }
+                _ => {}
+            }
+
+            current.push(c);
+        }
+
+        if !current.trim().is_empty() {
+            params.push(current);
+        }
+
+        let params: Vec<String> = params.iter()
+            .map(|param| {
+                let (param, rust) = param.split_once(':').unwrap();
+                declare(&c_type(rust), param.trim())
+            })
+            .collect();
+
+        let ret = match ret.trim().trim_end_matches('{').trim().strip_prefix("->") {
+            Some(rust) => c_type(rust),
+            None => "void".to_string(),
+        };
+
+        let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+        declarations.push((name.to_string(), format!("{}({});", declare(&ret, name), params)));
+    }
+
+    declarations.sort();
+    declarations.into_iter().map(|(_, declaration)| declaration).collect()
+}
+
+#[test]
+fn header_matches_the_c_api() {
+    let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs")).unwrap();
+    let header = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/inc/comet.h")).unwrap();
+
+    // everything that isn't a comment, an include or a blank line is a declaration
+    let declared: Vec<&str> = header.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("/*") && !line.starts_with(" *"))
+        .collect();
+
+    assert_eq!(declared, expected_declarations(&source), "inc/comet.h is out of date, regenerate it with cbindgen");
+}
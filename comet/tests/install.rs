@@ -0,0 +1,252 @@
+// installs and removes stars from a galaxy on disk into an install root in a temp dir
+//
+// the install root is global, so every test holds ROOT while it runs
+
+#![cfg(unix)]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use comet::package::Package;
+use comet::repo::Repository;
+use sha2::{Digest, Sha256};
+
+static ROOT: Mutex<()> = Mutex::new(());
+
+/// An install root with comet set up in it, and an empty galaxy next to it
+struct Root {
+    _guard: MutexGuard<'static, ()>,
+    _dir: tempfile::TempDir,
+    path: PathBuf,
+    galaxy: PathBuf,
+}
+
+impl Root {
+    fn new() -> Root {
+        // a failed test poisons the lock, which doesn't matter to the next one
+        let guard = ROOT.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("root");
+        let galaxy = dir.path().join("galaxy");
+
+        std::fs::create_dir_all(&galaxy).unwrap();
+        Repository::new(true).to_file(galaxy.join("repo.yml").to_string_lossy().to_string()).unwrap();
+
+        comet::set_install_root(path.to_string_lossy().to_string());
+        comet::setup().unwrap();
+
+        let config = format!(
+            "repositories:\n- file://{}\nkeep_package_files: false\nstorage_dir: /var/lib/comet\ntmp_dir: /tmp\n",
+            galaxy.display(),
+        );
+        std::fs::write(path.join("etc/comet/config.yml"), config).unwrap();
+
+        Root { _guard: guard, _dir: dir, path, galaxy }
+    }
+
+    /// Builds a star and adds it to the galaxy
+    ///
+    /// # Arguments
+    /// * `name` - The name of the package, version 1.0.0
+    /// * `dependencies` - The names of the packages it depends on
+    /// * `files` - The payload, as system paths and their contents
+    /// * `install` - The install script
+    /// * `remove` - The remove script
+    fn publish(&self, name: &str, dependencies: &[&str], files: &[(&str, &str)], install: &str, remove: &str) {
+        self.publish_star(name, dependencies, files, &[("install", install), ("remove", remove)]);
+    }
+
+    /// Builds a star with a `post-remove` script and adds it to the galaxy, see `publish`
+    fn publish_with_post_remove(&self, name: &str, files: &[(&str, &str)], install: &str, remove: &str, post_remove: &str) {
+        self.publish_star(name, &[], files, &[("install", install), ("remove", remove), ("post-remove", post_remove)]);
+    }
+
+    /// Builds a star with the given scripts, named like they are in the star, and adds it to the galaxy
+    fn publish_star(&self, name: &str, dependencies: &[&str], files: &[(&str, &str)], scripts: &[(&str, &str)]) {
+        let dependencies: HashMap<String, String> = dependencies.iter().map(|name| (name.to_string(), "1.0.0".to_string())).collect();
+        let mut package = Package::new(name.to_string(), String::new(), "1.0.0".to_string(), dependencies, "MIT".to_string(), Vec::new());
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, contents: &str, mode: u32| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            builder.append_data(&mut header, path, contents.as_bytes()).unwrap();
+        };
+
+        append("info.yml", &package.to_string(), 0o644);
+
+        for (script, contents) in scripts {
+            append(script, contents, 0o755);
+        }
+
+        for (path, contents) in files {
+            append(&format!("package{}", path), contents, 0o644);
+        }
+
+        let star = builder.into_inner().unwrap();
+        std::fs::write(self.galaxy.join(format!("{}.star", name)), &star).unwrap();
+
+        let repo_file = self.galaxy.join("repo.yml").to_string_lossy().to_string();
+        let mut repo = Repository::from_file(repo_file.clone()).unwrap();
+
+        package.checksum = Some(format!("{:x}", Sha256::digest(&star)));
+        repo.add_package(package);
+        repo.to_file(repo_file).unwrap();
+
+        comet::update_cache_file().unwrap();
+    }
+
+    /// A system path under the install root
+    fn join(&self, path: &str) -> PathBuf {
+        self.path.join(path.trim_start_matches('/'))
+    }
+}
+
+fn install(package: &str) -> comet::error::Result<comet::InstallResult> {
+    comet::install_package(package.to_string(), false, false, false)
+}
+
+#[test]
+fn install_then_remove() {
+    let root = Root::new();
+    root.publish("hello", &[], &[("/usr/bin/hello", "#!/bin/sh\necho hello\n")], "", "");
+
+    install("hello").unwrap();
+
+    assert_eq!(std::fs::read_to_string(root.join("/usr/bin/hello")).unwrap(), "#!/bin/sh\necho hello\n");
+    assert_eq!(comet::list_packages().unwrap().get("hello").map(String::as_str), Some("1.0.0"));
+
+    comet::remove_package("hello".to_string(), false).unwrap();
+
+    // the directories only the package needed go with it
+    assert!(!root.join("/usr").exists());
+    assert!(!comet::list_packages().unwrap().contains_key("hello"));
+}
+
+#[test]
+fn install_brings_dependencies_along() {
+    let root = Root::new();
+    root.publish("libhello", &[], &[("/usr/lib/libhello.so", "lib")], "", "");
+    root.publish("hello", &["libhello"], &[("/usr/bin/hello", "bin")], "", "");
+
+    let result = install("hello").unwrap();
+
+    let mut fetched: Vec<&str> = result.downloads.iter().map(|(package, _)| package.as_str()).collect();
+    fetched.sort();
+    assert_eq!(fetched, vec!["hello", "libhello"]);
+
+    assert!(root.join("/usr/lib/libhello.so").is_file());
+    assert!(root.join("/usr/bin/hello").is_file());
+
+    // libhello is still needed
+    assert!(comet::remove_package("libhello".to_string(), false).is_err());
+    assert!(root.join("/usr/lib/libhello.so").is_file());
+}
+
+#[test]
+fn failed_install_script_leaves_nothing_behind() {
+    let root = Root::new();
+    root.publish("broken", &[], &[("/usr/bin/broken", "bin")], "exit 1\n", "");
+
+    assert!(matches!(install("broken"), Err(comet::error::Error::Script { .. })));
+
+    assert!(!root.join("/usr").exists());
+    assert!(!comet::list_packages().unwrap().contains_key("broken"));
+}
+
+#[test]
+fn failed_remove_script_keeps_the_package() {
+    let root = Root::new();
+    root.publish("stuck", &[], &[("/usr/bin/stuck", "bin")], "", "exit 1\n");
+
+    install("stuck").unwrap();
+
+    assert!(comet::remove_package("stuck".to_string(), false).is_err());
+
+    assert!(root.join("/usr/bin/stuck").is_file());
+    assert!(comet::list_packages().unwrap().contains_key("stuck"));
+    assert!(Path::new(&root.join("/var/lib/comet/manifests/stuck.yml")).is_file());
+}
+
+#[test]
+fn failed_removal_runs_the_install_script_again() {
+    let root = Root::new();
+
+    // the remove script undoes the install script, and then removing fails
+    root.publish_with_post_remove(
+        "service",
+        &[("/usr/bin/service", "bin")],
+        "echo enabled > \"$COMET_ROOT/enabled\"\n",
+        "rm \"$COMET_ROOT/enabled\"\n",
+        "exit 1\n",
+    );
+
+    install("service").unwrap();
+    assert!(root.join("/enabled").is_file());
+
+    assert!(comet::remove_package("service".to_string(), false).is_err());
+
+    assert!(root.join("/enabled").is_file());
+    assert!(root.join("/usr/bin/service").is_file());
+    assert!(comet::list_packages().unwrap().contains_key("service"));
+}
+
+#[test]
+fn reinstall_uses_the_package_cache() {
+    let root = Root::new();
+    root.publish("hello", &[], &[("/usr/bin/hello", "bin")], "", "");
+
+    install("hello").unwrap();
+    comet::remove_package("hello".to_string(), false).unwrap();
+
+    // keep_package_files is off, which only goes for stars in the temp directory
+    assert_eq!(comet::package_cache_usage().unwrap().stars, 1);
+
+    // with the galaxy gone, the star can only come from the package cache
+    std::fs::remove_file(root.galaxy.join("hello.star")).unwrap();
+
+    let result = install("hello").unwrap();
+
+    let cache_dir = root.join("/var/lib/comet/stars").to_string_lossy().to_string();
+    assert_eq!(result.downloads, vec![("hello".to_string(), cache_dir)]);
+    assert!(root.join("/usr/bin/hello").is_file());
+}
+
+#[test]
+fn failed_autoremove_keeps_every_orphan() {
+    let root = Root::new();
+    root.publish("libstuck", &[], &[("/usr/lib/libstuck.so", "lib")], "", "exit 1\n");
+    root.publish("app", &["libstuck"], &[("/usr/bin/app", "bin")], "", "");
+
+    install("app").unwrap();
+    comet::mark_package("app".to_string(), comet::package::InstallReason::Dependency).unwrap();
+
+    // app is removed first and put back when libstuck's remove script fails
+    assert!(matches!(comet::autoremove_packages(), Err(comet::error::Error::Script { .. })));
+
+    let installed = comet::list_packages().unwrap();
+    assert!(installed.contains_key("app") && installed.contains_key("libstuck"));
+    assert!(root.join("/usr/bin/app").is_file());
+    assert!(root.join("/usr/lib/libstuck.so").is_file());
+    assert!(root.join("/var/lib/comet/manifests/app.yml").is_file());
+}
+
+#[test]
+fn autoremove_removes_what_nothing_needs() {
+    let root = Root::new();
+    root.publish("libhello", &[], &[("/usr/lib/libhello.so", "lib")], "", "");
+    root.publish("hello", &["libhello"], &[("/usr/bin/hello", "bin")], "", "");
+
+    install("hello").unwrap();
+
+    // libhello came along as a dependency, and hello still needs it
+    assert!(comet::autoremove_packages().unwrap().is_empty());
+
+    comet::mark_package("hello".to_string(), comet::package::InstallReason::Dependency).unwrap();
+    assert_eq!(comet::autoremove_packages().unwrap(), vec!["hello", "libhello"]);
+
+    let installed = comet::list_packages().unwrap();
+    assert!(!installed.contains_key("hello") && !installed.contains_key("libhello"));
+    assert!(!root.join("/usr").exists());
+}
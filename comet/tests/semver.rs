@@ -0,0 +1,143 @@
+use std::cmp::Ordering;
+use comet::package::{Identifier, SemVer};
+use proptest::prelude::*;
+
+fn identifier() -> impl Strategy<Value = Identifier> {
+    prop_oneof![
+        any::<u64>().prop_map(Identifier::Numeric),
+        // at least one letter or hyphen, so it can't be mistaken for a number
+        "[0-9A-Za-z-]{0,4}[A-Za-z-][0-9A-Za-z-]{0,4}".prop_map(Identifier::AlphaNumeric),
+    ]
+}
+
+fn version() -> impl Strategy<Value = SemVer> {
+    (
+        any::<u64>(),
+        any::<u64>(),
+        any::<u64>(),
+        prop::collection::vec(identifier(), 0..4),
+        prop::collection::vec("[0-9A-Za-z-]{1,6}", 0..3),
+    ).prop_map(|(major, minor, patch, pre, build)| SemVer { major, minor, patch, pre, build })
+}
+
+/// Versions that only differ in small numbers and a few identifiers, so comparisons often reach the pre-release
+fn close_version() -> impl Strategy<Value = SemVer> {
+    let identifier = prop_oneof![
+        (0u64..3).prop_map(Identifier::Numeric),
+        prop_oneof!["alpha", "beta", "rc"].prop_map(Identifier::AlphaNumeric),
+    ];
+
+    (0u64..2, 0u64..2, 0u64..2, prop::collection::vec(identifier, 0..3))
+        .prop_map(|(major, minor, patch, pre)| SemVer { major, minor, patch, pre, build: Vec::new() })
+}
+
+proptest! {
+    #[test]
+    fn display_round_trips(version in version()) {
+        let parsed: SemVer = version.to_string().parse().unwrap();
+
+        prop_assert_eq!(&parsed, &version);
+        prop_assert_eq!(&parsed.build, &version.build);
+        prop_assert_eq!(parsed.to_string(), version.to_string());
+    }
+
+    #[test]
+    fn serde_round_trips(version in version()) {
+        let yaml = serde_yaml::to_string(&version).unwrap();
+        let parsed: SemVer = serde_yaml::from_str(&yaml).unwrap();
+
+        prop_assert_eq!(parsed.to_string(), version.to_string());
+    }
+
+    #[test]
+    fn parsing_never_panics(version in "\\PC{0,20}") {
+        let _ = SemVer::from_string(version);
+    }
+
+    #[test]
+    fn build_metadata_is_ignored(version in version(), build in prop::collection::vec("[0-9A-Za-z-]{1,6}", 0..3)) {
+        let other = SemVer { build, ..version.clone() };
+
+        prop_assert_eq!(version.cmp(&other), Ordering::Equal);
+        prop_assert_eq!(version, other);
+    }
+
+    #[test]
+    fn prerelease_comes_before_release(version in version()) {
+        prop_assume!(version.is_prerelease());
+
+        let release = SemVer { pre: Vec::new(), ..version.clone() };
+
+        prop_assert!(version < release);
+    }
+
+    #[test]
+    fn numeric_identifiers_compare_as_numbers(a in any::<u64>(), b in any::<u64>()) {
+        let a_version: SemVer = format!("1.0.0-rc.{}", a).parse().unwrap();
+        let b_version: SemVer = format!("1.0.0-rc.{}", b).parse().unwrap();
+
+        prop_assert_eq!(a_version.cmp(&b_version), a.cmp(&b));
+    }
+
+    #[test]
+    fn ordering_is_antisymmetric(a in close_version(), b in close_version()) {
+        prop_assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+        prop_assert_eq!(a == b, a.cmp(&b) == Ordering::Equal);
+    }
+
+    #[test]
+    fn ordering_is_transitive(a in close_version(), b in close_version(), c in close_version()) {
+        if a <= b && b <= c {
+            prop_assert!(a <= c);
+        }
+    }
+
+    #[test]
+    fn leading_zeros_are_rejected(number in 0u64..1000) {
+        let major = SemVer::from_string(format!("0{}.0.0", number));
+        let pre = SemVer::from_string(format!("1.0.0-0{}", number));
+        let build = SemVer::from_string(format!("1.0.0+0{}", number));
+
+        prop_assert!(major.is_err());
+        prop_assert!(pre.is_err());
+        // build metadata isn't a number, so leading zeros are fine there
+        prop_assert!(build.is_ok());
+    }
+}
+
+#[test]
+fn precedence_follows_the_spec() {
+    let versions = [
+        "1.0.0-alpha",
+        "1.0.0-alpha.1",
+        "1.0.0-alpha.beta",
+        "1.0.0-beta",
+        "1.0.0-beta.2",
+        "1.0.0-beta.11",
+        "1.0.0-rc.1",
+        "1.0.0",
+        "2.0.0",
+        "2.1.0",
+        "2.1.1",
+    ];
+
+    for pair in versions.windows(2) {
+        let lower: SemVer = pair[0].parse().unwrap();
+        let higher: SemVer = pair[1].parse().unwrap();
+
+        assert!(lower < higher, "{} should come before {}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn invalid_versions_are_rejected() {
+    for version in ["", "1", "1.0", "1.0.0.0", "1.0.0-", "1.0.0+", "1.0.0-rc..1", "1.0.0-rc_1", "v1.0.0", "-1.0.0", "1.0.0+build+2"] {
+        assert!(SemVer::from_string(version.to_string()).is_err(), "{} should be rejected", version);
+    }
+}
+
+#[test]
+fn release_has_no_trailing_dash() {
+    assert_eq!(SemVer::from_string("1.0.0".to_string()).unwrap().to_string(), "1.0.0");
+    assert_eq!(SemVer::from_string("1.0.0-rc.1+build.5".to_string()).unwrap().to_string(), "1.0.0-rc.1+build.5");
+}
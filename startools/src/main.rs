@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use clap::{Parser, Subcommand};
-use comet;
 use comet::package::Package;
+use comet::star::{Compression, Encoder};
 use sha2::{Sha256, Digest};
 
 #[derive(Parser)]
@@ -18,6 +18,12 @@ enum Commands {
     #[command()]
     Build {
         name: String,
+
+        #[arg(short, long, default_value = "zstd", help = "The compression to use: none, gzip, xz or zstd")]
+        compression: Compression,
+
+        #[arg(short, long, help = "The compression level, defaults to a sensible level for the codec")]
+        level: Option<u32>,
     },
 
     #[command()]
@@ -39,6 +45,19 @@ enum Commands {
     UpdateRepo {
         repo_file: String,
         package_file: String,
+    },
+
+    #[command(about = "Generate a key pair for signing stars and repo.yml files")]
+    Keygen {
+        name: String,
+    },
+
+    #[command(about = "Sign a star or repo.yml file, writing the signature to <file>.sig")]
+    Sign {
+        #[arg(short, long, help = "The secret key file created by keygen")]
+        key: String,
+
+        file: String,
     }
 }
 
@@ -59,7 +78,10 @@ fn check_package(package: String) -> bool {
         std::process::exit(1);
     });
 
-    let _package = comet::package::Package::from_string(package_file);
+    if let Err(err) = comet::package::Package::from_string(package_file) {
+        println!("Error while parsing package file: {}", err);
+        return false;
+    }
 
     // if there was an error, we wouldn't be here
     // so we can say that the info.yaml file exists and is valid
@@ -90,6 +112,41 @@ fn check_package(package: String) -> bool {
     true
 }
 
+/// Adds everything under `dir` to the star, named relative to the package directory
+///
+/// Symlinks are stored as symlinks rather than followed, and every entry is owned by root, since
+/// comet applies the owners in the star when it installs the package. Modes are kept as they are,
+/// so setuid binaries and private files survive.
+fn append_tree<W: Write>(builder: &mut tar::Builder<W>, dir: &std::path::Path, name: &std::path::Path) -> std::io::Result<()> {
+    // sort the entries so the same package directory always builds the same star
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let entry_name = name.join(entry.file_name());
+        let metadata = std::fs::symlink_metadata(&path)?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, tar::HeaderMode::Complete);
+        header.set_uid(0);
+        header.set_gid(0);
+
+        if metadata.file_type().is_symlink() {
+            header.set_size(0);
+            builder.append_link(&mut header, &entry_name, std::fs::read_link(&path)?)?;
+        } else if metadata.is_dir() {
+            header.set_size(0);
+            builder.append_data(&mut header, &entry_name, std::io::empty())?;
+            append_tree(builder, &path, &entry_name)?;
+        } else {
+            builder.append_data(&mut header, &entry_name, File::open(&path)?)?;
+        }
+    }
+
+    Ok(())
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -140,7 +197,7 @@ fn main() {
             });
         },
 
-        Commands::Build { name } => {
+        Commands::Build { name, compression, level } => {
             let current_dir = std::env::current_dir().unwrap_or_else(|err| {
                 println!("Error while getting current directory: {}", err);
                 std::process::exit(1);
@@ -174,22 +231,38 @@ fn main() {
             });
 
             // lets load the package info.yml file (for later)
-            let mut package_file = Package::from_file("info.yaml".to_string());
+            let mut package_file = Package::from_file("info.yaml".to_string()).unwrap_or_else(|err| {
+                println!("Error while reading package file: {}", err);
+                std::process::exit(1);
+            });
 
-            let mut a = tar::Builder::new(tarball);
+            // compress the tarball as it is written
+            let level = level.unwrap_or(compression.default_level());
 
-            a.append_dir_all("", ".").unwrap_or_else(|err| {
-                println!("Error while appending directory: {}", err);
+            let encoder = Encoder::new(tarball, compression, level).unwrap_or_else(|err| {
+                println!("Error while creating tarball: {}", err);
                 std::process::exit(1);
             });
 
-            // remove the '.' directory from the tarball
+            let mut a = tar::Builder::new(encoder);
+            a.follow_symlinks(false);
+
+            // the '.' directory itself is left out of the tarball
+            append_tree(&mut a, std::path::Path::new("."), std::path::Path::new("")).unwrap_or_else(|err| {
+                println!("Error while appending directory: {}", err);
+                std::process::exit(1);
+            });
 
-            a.finish().unwrap_or_else(|err| {
+            let encoder = a.into_inner().unwrap_or_else(|err| {
                 println!("Error while finishing tarball: {}", err);
                 std::process::exit(1);
             });
 
+            encoder.finish().unwrap_or_else(|err| {
+                println!("Error while compressing tarball: {}", err);
+                std::process::exit(1);
+            });
+
             let mut hasher = Sha256::new();
 
             // go back to the original directory
@@ -219,7 +292,7 @@ fn main() {
 
             // output the package file contents for remote repositories to use
             println!("The following is the package file contents for remote repositories to use:");
-            println!("{}", package_file.to_string());
+            println!("{}", package_file);
         },
 
         Commands::Check { name } => {
@@ -378,13 +451,31 @@ fn main() {
 
         Commands::UpdateRepo {repo_file, package_file } => {
             // load the repo file
-            let mut repo = comet::repo::Repository::from_file(repo_file.clone());
+            let mut repo = comet::repo::Repository::from_file(repo_file.clone()).unwrap_or_else(|err| {
+                println!("Error while reading repo file: {}", err);
+                std::process::exit(1);
+            });
+
+            // the checksum is of the star itself, which is what comet downloads and checks
+            let mut star = std::fs::File::open(&package_file).unwrap_or_else(|err| {
+                println!("Error while opening package file: {}", err);
+                std::process::exit(1);
+            });
+
+            let mut hasher = Sha256::new();
+
+            std::io::copy(&mut star, &mut hasher).unwrap_or_else(|err| {
+                println!("Error while reading package file: {}", err);
+                std::process::exit(1);
+            });
+
+            let checksum = format!("{:x}", hasher.finalize());
 
             // load the package tar file and grab the package file
-            let mut package_tar = tar::Archive::new(std::fs::File::open(package_file.clone()).unwrap_or_else(|err| {
+            let mut package_tar = comet::star::open(&package_file).unwrap_or_else(|err| {
                 println!("Error while opening package file: {}", err);
                 std::process::exit(1);
-            }));
+            });
 
             // package file is called "info.yaml", so filter for that
 
@@ -393,7 +484,9 @@ fn main() {
                 std::process::exit(1);
             });
 
-            let mut package_file = files.filter("info.yaml").next().unwrap_or_else(|| {
+            let mut package_file = files.filter_map(|file| file.ok()).find(|file| {
+                file.path().map(|path| path == std::path::Path::new("info.yaml")).unwrap_or(false)
+            }).unwrap_or_else(|| {
                 println!("Error while reading package file");
                 std::process::exit(1);
             });
@@ -405,22 +498,92 @@ fn main() {
                 std::process::exit(1);
             });
 
-            let mut package = comet::package::Package::from_string(package_file_contents);
-
-            // now run a checksum on the package file and set the checksum field to that
-            let mut hasher = Sha256::new();
+            let mut package = comet::package::Package::from_string(package_file_contents.clone()).unwrap_or_else(|err| {
+                println!("Error while parsing package file: {}", err);
+                std::process::exit(1);
+            });
 
-            hasher.update(package_file_contents.as_bytes());
-            let checksum = format!("{:x}", hasher.finalize());
             package.checksum = Some(checksum.clone());
 
             // now add the package to the repo
             repo.add_package(package);
 
             // now write the repo file
-            repo.to_file(repo_file.clone());
+            repo.to_file(repo_file.clone()).unwrap_or_else(|err| {
+                println!("Error while writing repo file: {}", err);
+                std::process::exit(1);
+            });
 
             println!("Package added successfully");
         }
+
+        Commands::Keygen { name } => {
+            let secret_file = format!("{}.key", name);
+            let public_file = format!("{}.pub", name);
+
+            if std::path::Path::new(&secret_file).exists() {
+                println!("{} already exists", secret_file);
+                std::process::exit(1);
+            }
+
+            let (secret_key, public_key) = comet::signature::generate_key().unwrap_or_else(|err| {
+                println!("Error while generating key: {}", err);
+                std::process::exit(1);
+            });
+
+            // nobody but the owner should be able to read the secret key
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+
+            let mut file = options.open(&secret_file).unwrap_or_else(|err| {
+                println!("Error while creating secret key file: {}", err);
+                std::process::exit(1);
+            });
+
+            file.write_all(secret_key.as_bytes()).unwrap_or_else(|err| {
+                println!("Error while writing secret key file: {}", err);
+                std::process::exit(1);
+            });
+
+            std::fs::write(&public_file, public_key).unwrap_or_else(|err| {
+                println!("Error while writing public key file: {}", err);
+                std::process::exit(1);
+            });
+
+            println!("Secret key written to {}, keep it safe", secret_file);
+            println!("Public key written to {}, copy it to the trusted keys directory of every comet that uses your galaxy", public_file);
+        },
+
+        Commands::Sign { key, file } => {
+            let secret_key = std::fs::read_to_string(&key).unwrap_or_else(|err| {
+                println!("Error while reading secret key file: {}", err);
+                std::process::exit(1);
+            });
+
+            let data = File::open(&file).unwrap_or_else(|err| {
+                println!("Error while opening {}: {}", file, err);
+                std::process::exit(1);
+            });
+
+            let signature = comet::signature::sign(&secret_key, &file, std::io::BufReader::new(data)).unwrap_or_else(|err| {
+                println!("Error while signing {}: {}", file, err);
+                std::process::exit(1);
+            });
+
+            let signature_file = format!("{}.sig", file);
+
+            std::fs::write(&signature_file, signature).unwrap_or_else(|err| {
+                println!("Error while writing signature file: {}", err);
+                std::process::exit(1);
+            });
+
+            println!("Signature written to {}", signature_file);
+        }
     }
 }
\ No newline at end of file